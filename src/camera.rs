@@ -1,5 +1,6 @@
 use cgmath::*;
 use winit::event::*;
+use crate::input::Action;
 use winit::dpi::PhysicalPosition;
 use instant::Duration;
 use std::f32::consts::FRAC_PI_2;
@@ -14,6 +15,48 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
 
 const SAFE_FRAC_PI_2: f32 = FRAC_PI_2 - 0.0001;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlMode {
+    Fly,
+    Orbit,
+    Flight,
+}
+
+// input handling shared by the camera controllers, so they can be swapped at
+// runtime
+pub trait CameraControl {
+    // held actions, true if the controller used it
+    fn process_action(&mut self, action: Action, pressed: bool) -> bool {
+        self.process_axis(action, if pressed { 1.0 } else { 0.0 })
+    }
+    // how far a held action is pushed, from 0 to 1, for sticks and triggers
+    fn process_axis(&mut self, action: Action, amount: f32) -> bool;
+    // mouse movement while looking around
+    fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64);
+    // mouse movement while panning
+    fn process_pan(&mut self, _mouse_dx: f64, _mouse_dy: f64) {}
+    fn process_scroll(&mut self, delta: &MouseScrollDelta);
+    fn update_camera(&mut self, camera: &mut Camera, dt: Duration);
+    // how far away the thing the camera is looking at is, for sizing
+    // orthographic views, None when it isn't looking at anything in
+    // particular
+    fn focus_distance(&self) -> Option<f32> {
+        None
+    }
+}
+
+// scroll in pixels, negated so scrolling up is negative
+fn scroll_amount(delta: &MouseScrollDelta) -> f32 {
+    -match delta {
+        // assuming a line is 100 pixels
+        MouseScrollDelta::LineDelta(_, scroll) => scroll * 100.0,
+        MouseScrollDelta::PixelDelta(PhysicalPosition {
+            y: scroll,
+            ..
+        }) => *scroll as f32,
+    }
+}
+
 #[derive(Debug)]
 pub struct Camera {
     pub position: Point3<f32>,
@@ -38,26 +81,147 @@ impl Camera {
         }
     }
 
-    pub fn calc_matrix(&self) -> Matrix4<f32> {
+    pub fn forward(&self) -> Vector3<f32> {
         let (sin_pitch, cos_pitch) = self.pitch.0.sin_cos();
         let (sin_yaw, cos_yaw) = self.yaw.0.sin_cos();
 
+        Vector3::new(
+            cos_pitch * cos_yaw,
+            sin_pitch,
+            cos_pitch * sin_yaw
+        ).normalize()
+    }
+
+    pub fn calc_matrix(&self) -> Matrix4<f32> {
         Matrix4::look_to_rh(
             self.position,
-            Vector3::new(
-                cos_pitch * cos_yaw,
-                sin_pitch,
-                cos_pitch * sin_yaw
-            ).normalize(),
+            self.forward(),
             Vector3::unit_y(),
         )
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProjectionKind {
+    Perspective {
+        fovy: Rad<f32>,
+    },
+    // `height` is the world space height of the view volume, the width
+    // follows the aspect ratio
+    Orthographic {
+        height: f32,
+    },
+    // an asymmetric frustum given by its extents on the near plane, the
+    // aspect ratio is not applied but resizing scales the horizontal extents
+    OffAxis {
+        left: f32,
+        right: f32,
+        bottom: f32,
+        top: f32,
+    },
+    // already in wgpu clip space with the depth buffer's depth direction, the
+    // aspect ratio and clip planes are ignored, resizing scales clip space x
+    Custom(Matrix4<f32>),
+}
+
+const MIN_ORTHO_HEIGHT: f32 = 0.01;
+const ORTHO_ZOOM_SPEED: f32 = 0.001;
+// how far back the oblique projection pushes things, per unit of depth, and
+// which way on screen
+const OBLIQUE_DEPTH_SCALE: f32 = 0.5;
+const OBLIQUE_ANGLE: Deg<f32> = Deg(45.0);
+
+// A camera free to face any way, including rolling and looking straight up.
+// The identity orientation looks down -z with +y up.
+#[derive(Debug, Clone, Copy)]
+pub struct QuatCamera {
+    pub position: Point3<f32>,
+    pub orientation: Quaternion<f32>,
+}
+
+impl QuatCamera {
+    pub fn forward(&self) -> Vector3<f32> {
+        self.orientation.rotate_vector(-Vector3::unit_z())
+    }
+
+    pub fn right(&self) -> Vector3<f32> {
+        self.orientation.rotate_vector(Vector3::unit_x())
+    }
+
+    pub fn up(&self) -> Vector3<f32> {
+        self.orientation.rotate_vector(Vector3::unit_y())
+    }
+
+    // rotates around the camera's own axes
+    pub fn rotate_local(&mut self, yaw: Rad<f32>, pitch: Rad<f32>, roll: Rad<f32>) {
+        self.orientation = (
+            self.orientation
+                * Quaternion::from_angle_y(yaw)
+                * Quaternion::from_angle_x(pitch)
+                * Quaternion::from_angle_z(roll)
+        ).normalize();
+    }
+
+    pub fn calc_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from(self.orientation.conjugate())
+            * Matrix4::from_translation(-self.position.to_vec())
+    }
+}
+
+impl From<&Camera> for QuatCamera {
+    fn from(camera: &Camera) -> Self {
+        // yaw is measured from +x, the identity orientation faces -z
+        let yaw = Quaternion::from_angle_y(-camera.yaw - Rad(FRAC_PI_2));
+        Self {
+            position: camera.position,
+            orientation: yaw * Quaternion::from_angle_x(camera.pitch),
+        }
+    }
+}
+
+// drops the roll, looking straight up or down keeps a yaw of zero
+impl From<&QuatCamera> for Camera {
+    fn from(camera: &QuatCamera) -> Self {
+        let forward = camera.forward();
+        let pitch = forward.y.clamp(-1.0, 1.0).asin()
+            .clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2);
+        Self {
+            position: camera.position,
+            yaw: Rad(forward.z.atan2(forward.x)),
+            pitch: Rad(pitch),
+        }
+    }
+}
+
+// flips 0..1 depth around so the near plane ends up at 1
+#[rustfmt::skip]
+const REVERSE_Z_MATRIX: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, -1.0, 0.0,
+    0.0, 0.0, 1.0, 1.0,
+);
+
+// a perspective frustum with its far plane at infinity and reversed depth,
+// the near plane lands on 1 and depth falls towards 0 with distance
+fn infinite_reverse_frustum(left: f32, right: f32, bottom: f32, top: f32, znear: f32) -> Matrix4<f32> {
+    let width = right - left;
+    let height = top - bottom;
+    Matrix4::from_cols(
+        Vector4::new(2.0 * znear / width, 0.0, 0.0, 0.0),
+        Vector4::new(0.0, 2.0 * znear / height, 0.0, 0.0),
+        Vector4::new((right + left) / width, (top + bottom) / height, 0.0, -1.0),
+        Vector4::new(0.0, 0.0, znear, 0.0),
+    )
+}
+
 pub struct Projection {
     aspect: f32,
+    kind: ProjectionKind,
+    // what kinds without a field of view go back to when made perspective
     fovy: Rad<f32>,
     znear: f32,
+    // ignored by perspective projections with reverse-Z
     zfar: f32,
 }
 
@@ -71,28 +235,209 @@ impl Projection {
         znear: f32,
         zfar: f32,
     ) -> Self {
+        let fovy = fovy.into();
         Self {
             aspect: width as f32 / height as f32,
-            fovy: fovy.into(),
+            kind: ProjectionKind::Perspective { fovy },
+            fovy,
             znear,
             zfar,
         }
     }
 
     pub fn resize(&mut self, width: u32, height: u32) {
-        self.aspect = width as f32 / height as f32;
+        let aspect = width as f32 / height as f32;
+        // the kinds that don't apply the aspect ratio are stretched to fit
+        let scale = aspect / self.aspect;
+        match &mut self.kind {
+            ProjectionKind::OffAxis { left, right, .. } => {
+                *left *= scale;
+                *right *= scale;
+            }
+            ProjectionKind::Custom(matrix) => {
+                *matrix = Matrix4::from_nonuniform_scale(1.0 / scale, 1.0, 1.0) * *matrix;
+            }
+            _ => {}
+        }
+        self.aspect = aspect;
+    }
+
+    pub fn kind(&self) -> ProjectionKind {
+        self.kind
+    }
+
+    // Switches between perspective and orthographic, keeping things at
+    // `focus_distance` the same size on screen. Other kinds become
+    // perspective.
+    pub fn toggle_orthographic(&mut self, focus_distance: f32) {
+        let focus_distance = focus_distance.max(self.znear);
+        self.kind = match self.kind {
+            ProjectionKind::Perspective { fovy } => ProjectionKind::Orthographic {
+                height: 2.0 * focus_distance * (fovy.0 * 0.5).tan(),
+            },
+            ProjectionKind::Orthographic { height } => ProjectionKind::Perspective {
+                fovy: Rad(2.0 * (height * 0.5 / focus_distance).atan()),
+            },
+            _ => ProjectionKind::Perspective { fovy: self.fovy },
+        };
+    }
+
+    // Switches a perspective projection to an off-axis one with the same
+    // field of view, its centre moved up by `shift` times its height, and
+    // back again. Shifting the lens rather than tilting the camera keeps
+    // vertical lines vertical. Other kinds are left alone.
+    pub fn toggle_lens_shift(&mut self, shift: f32) {
+        self.kind = match self.kind {
+            ProjectionKind::Perspective { fovy } => {
+                let top = self.znear * (fovy.0 * 0.5).tan();
+                let right = top * self.aspect;
+                ProjectionKind::OffAxis {
+                    left: -right,
+                    right,
+                    bottom: top * (2.0 * shift - 1.0),
+                    top: top * (2.0 * shift + 1.0),
+                }
+            }
+            ProjectionKind::OffAxis { bottom, top, .. } => ProjectionKind::Perspective {
+                fovy: Rad(2.0 * ((top - bottom) * 0.5 / self.znear).atan()),
+            },
+            kind => kind,
+        };
+    }
+
+    // Switches to an oblique (cabinet) projection, an orthographic one with
+    // depth slanted up and to the right, keeping things at `focus_distance`
+    // where they were and the same size on screen. Switching back goes
+    // perspective.
+    pub fn toggle_oblique(&mut self, focus_distance: f32) {
+        let focus_distance = focus_distance.max(self.znear);
+        let height = match self.kind {
+            ProjectionKind::Custom(_) => {
+                self.kind = ProjectionKind::Perspective { fovy: self.fovy };
+                return;
+            }
+            ProjectionKind::Perspective { fovy } => 2.0 * focus_distance * (fovy.0 * 0.5).tan(),
+            ProjectionKind::Orthographic { height } => height,
+            ProjectionKind::OffAxis { bottom, top, .. } => {
+                (top - bottom) * focus_distance / self.znear
+            }
+        };
+        self.kind = ProjectionKind::Orthographic { height };
+        // view space looks down -z, so farther things get pushed further,
+        // with the focus plane staying put
+        let (sin, cos) = Rad::from(OBLIQUE_ANGLE).0.sin_cos();
+        let (x, y) = (-OBLIQUE_DEPTH_SCALE * cos, -OBLIQUE_DEPTH_SCALE * sin);
+        let shear = Matrix4::from_cols(
+            Vector4::unit_x(),
+            Vector4::unit_y(),
+            Vector4::new(x, y, 1.0, 0.0),
+            Vector4::new(x * focus_distance, y * focus_distance, 0.0, 1.0),
+        );
+        self.kind = ProjectionKind::Custom(self.calc_matrix() * shear);
+    }
+
+    // orthographic views zoom by scaling their extents, returns false for
+    // the other kinds so the camera controller can move instead
+    pub fn zoom(&mut self, delta: &MouseScrollDelta) -> bool {
+        match &mut self.kind {
+            ProjectionKind::Orthographic { height } => {
+                let factor = 1.0 + scroll_amount(delta) * ORTHO_ZOOM_SPEED;
+                *height = (*height * factor.max(0.1)).max(MIN_ORTHO_HEIGHT);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // fraction of the viewport height covered by a sphere of `radius` at
+    // `distance` from the camera
+    pub fn screen_size(&self, radius: f32, distance: f32) -> f32 {
+        let matrix = self.calc_matrix();
+        let size = radius * matrix.y.y;
+        // orthographic projections leave w alone, so distance doesn't matter
+        if matrix.z.w == 0.0 {
+            size
+        } else {
+            size / distance.max(f32::EPSILON)
+        }
     }
 
     pub fn calc_matrix(&self) -> Matrix4<f32> {
-        OPENGL_TO_WGPU_MATRIX * perspective(
-            self.fovy, 
-            self.aspect, 
-            self.znear, 
-            self.zfar
-        )
+        // has to match the depth buffer's compare function and clear value
+        if crate::texture::Texture::REVERSE_Z {
+            self.calc_reverse_z_matrix()
+        } else {
+            self.calc_standard_matrix()
+        }
+    }
+
+    // Perspective and off-axis projections get an infinite far plane.
+    // Orthographic ones keep theirs, there's nothing to gain from depth
+    // that's linear anyway. Custom matrices are used as they are.
+    fn calc_reverse_z_matrix(&self) -> Matrix4<f32> {
+        match self.kind {
+            ProjectionKind::Perspective { fovy } => {
+                let top = self.znear * (fovy.0 * 0.5).tan();
+                let right = top * self.aspect;
+                infinite_reverse_frustum(-right, right, -top, top, self.znear)
+            }
+            ProjectionKind::OffAxis { left, right, bottom, top } => {
+                infinite_reverse_frustum(left, right, bottom, top, self.znear)
+            }
+            ProjectionKind::Orthographic { .. } => {
+                REVERSE_Z_MATRIX * self.calc_standard_matrix()
+            }
+            ProjectionKind::Custom(matrix) => matrix,
+        }
     }
+
+    fn calc_standard_matrix(&self) -> Matrix4<f32> {
+        let opengl_matrix = match self.kind {
+            ProjectionKind::Perspective { fovy } => {
+                perspective(fovy, self.aspect, self.znear, self.zfar)
+            }
+            ProjectionKind::Orthographic { height } => {
+                let half_height = height * 0.5;
+                let half_width = half_height * self.aspect;
+                ortho(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    self.znear,
+                    self.zfar,
+                )
+            }
+            ProjectionKind::OffAxis { left, right, bottom, top } => {
+                frustum(left, right, bottom, top, self.znear, self.zfar)
+            }
+            ProjectionKind::Custom(matrix) => return matrix,
+        };
+        OPENGL_TO_WGPU_MATRIX * opengl_matrix
+    }
+}
+
+// mouse and scroll deltas are in pixels, the sensitivity was tuned against
+// 60 fps frames back when it was scaled by the frame time
+const PIXEL_SCALE: f32 = 1.0 / 60.0;
+
+// Takes this frame's share of `pending` when easing it out over roughly
+// `smoothing` seconds. What's left decays exponentially, so the result
+// doesn't depend on the frame rate. No smoothing takes it all at once.
+fn take_smoothed(pending: &mut f32, smoothing: f32, dt: f32) -> f32 {
+    let amount = if smoothing > 0.0 {
+        *pending * (1.0 - (-dt / smoothing).exp())
+    } else {
+        *pending
+    };
+    *pending -= amount;
+    amount
 }
 
+// Flies the camera with the move, jump and crouch actions, looks around with
+// the mouse. Held actions set a target velocity the camera accelerates
+// towards and it coasts to a stop once they're released. Sprint and slow
+// scale the speed.
 #[derive(Debug)]
 pub struct CameraController {
     amount_left: f32,
@@ -101,11 +446,25 @@ pub struct CameraController {
     amount_backward: f32,
     amount_up: f32,
     amount_down: f32,
+    sprint: bool,
+    slow: bool,
+    velocity: Vector3<f32>,
+    // mouse movement and scroll not yet applied to the camera
     rotate_horizontal: f32,
     rotate_vertical: f32,
     scroll: f32,
-    speed: f32,
-    sensitivity: f32,
+    // top speed, in units per second
+    pub speed: f32,
+    pub sensitivity: f32,
+    // units per second squared
+    pub acceleration: f32,
+    // how fast the velocity decays without input, per second
+    pub damping: f32,
+    // in seconds, 0 turns smoothing off
+    pub mouse_smoothing: f32,
+    pub scroll_smoothing: f32,
+    pub sprint_multiplier: f32,
+    pub slow_multiplier: f32,
 }
 
 impl CameraController {
@@ -117,110 +476,136 @@ impl CameraController {
             amount_backward: 0.0,
             amount_up: 0.0,
             amount_down: 0.0,
+            sprint: false,
+            slow: false,
+            velocity: Vector3::zero(),
             rotate_horizontal: 0.0,
             rotate_vertical: 0.0,
             scroll: 0.0,
             speed,
             sensitivity,
+            acceleration: speed * 5.0,
+            damping: 8.0,
+            mouse_smoothing: 0.03,
+            scroll_smoothing: 0.1,
+            sprint_multiplier: 3.0,
+            slow_multiplier: 0.25,
+        }
+    }
+
+    fn target_velocity(&self, camera: &Camera) -> Vector3<f32> {
+        let (yaw_sin, yaw_cos) = camera.yaw.0.sin_cos();
+        let forward = Vector3::new(yaw_cos, 0.0, yaw_sin).normalize();
+        let right = Vector3::new(-yaw_sin, 0.0, yaw_cos).normalize();
+        // since we dont use roll, up is always along y
+        let direction = forward * (self.amount_forward - self.amount_backward)
+            + right * (self.amount_right - self.amount_left)
+            + Vector3::unit_y() * (self.amount_up - self.amount_down);
+        if direction.magnitude2() == 0.0 {
+            return Vector3::zero();
+        }
+        // diagonals aren't any faster, a stick pushed halfway is slower
+        let direction = if direction.magnitude2() > 1.0 {
+            direction.normalize()
+        } else {
+            direction
+        };
+
+        let mut speed = self.speed;
+        if self.sprint {
+            speed *= self.sprint_multiplier;
+        }
+        if self.slow {
+            speed *= self.slow_multiplier;
         }
-    }  
+        direction * speed
+    }
+}
 
-    pub fn process_keyboard(
-        &mut self, 
-        key: VirtualKeyCode, 
-        state: ElementState
-    ) -> bool {
-        let amount = if state == ElementState::Pressed { 1.0 } else { 0.0 };
-        match key {
-            VirtualKeyCode::W | VirtualKeyCode::Up => {
+impl CameraControl for CameraController {
+    fn process_axis(&mut self, action: Action, amount: f32) -> bool {
+        match action {
+            Action::MoveForward => {
                 self.amount_forward = amount;
                 true
             }
 
-            VirtualKeyCode::S | VirtualKeyCode::Down => {
+            Action::MoveBackward => {
                 self.amount_backward = amount;
                 true
             }
 
-            VirtualKeyCode::A | VirtualKeyCode::Left => {
+            Action::MoveLeft => {
                 self.amount_left = amount;
                 true
             }
 
-            VirtualKeyCode::D | VirtualKeyCode::Right => {
+            Action::MoveRight => {
                 self.amount_right = amount;
                 true
             }            
 
-            VirtualKeyCode::Space => {
+            Action::Jump => {
                 self.amount_up = amount;
                 true
             }
 
-            VirtualKeyCode::LShift => {
+            Action::Crouch => {
                 self.amount_down = amount;
                 true
             }
 
+            Action::Sprint => {
+                self.sprint = amount > 0.0;
+                true
+            }
+
+            Action::Slow => {
+                self.slow = amount > 0.0;
+                true
+            }
+
             _ => false
         }
     }
 
-    pub fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
-        self.rotate_horizontal = mouse_dx as f32;
-        self.rotate_vertical = mouse_dy as f32;
+    fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
+        self.rotate_horizontal += mouse_dx as f32;
+        self.rotate_vertical += mouse_dy as f32;
     }
 
-    pub fn process_scroll(&mut self, delta: &MouseScrollDelta) {
-        self.scroll = -match delta {
-            // assuming a line is 100 pixels
-            MouseScrollDelta::LineDelta(_, scroll) => scroll * 100.0,
-            MouseScrollDelta::PixelDelta(PhysicalPosition {
-                y: scroll,
-                ..
-            }) => *scroll as f32,
-        };
+    fn process_scroll(&mut self, delta: &MouseScrollDelta) {
+        self.scroll += scroll_amount(delta);
     }
 
-    pub fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
+    fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
         let dt = dt.as_secs_f32();
 
-        // move forward/backward and left/right
-        let (yaw_sin, yaw_cos) = camera.yaw.0.sin_cos();
-        let forward = Vector3::new(yaw_cos, 0.0, yaw_sin).normalize();
-        let right = Vector3::new(-yaw_sin, 0.0, yaw_cos).normalize();
-        camera.position += forward * (
-            self.amount_forward - self.amount_backward
-        ) * self.speed * dt;
-        camera.position += right * (
-            self.amount_right - self.amount_left
-        ) * self.speed * dt;
-        
-        // move in/out (zoom)
-        let (pitch_sin, pitch_cos) = camera.pitch.0.sin_cos();
-        let scrollward = Vector3::new(
-            pitch_cos * yaw_cos, 
-            pitch_sin, 
-            pitch_cos * yaw_sin
-        ).normalize();
-        camera.position += scrollward * self.scroll * self.speed * self.sensitivity * dt;
-        self.scroll = 0.0;
+        // accelerate towards the velocity the keys ask for, or coast to a
+        // stop when none are held
+        let target = self.target_velocity(camera);
+        if target == Vector3::zero() {
+            self.velocity *= (-self.damping * dt).exp();
+        } else {
+            let change = target - self.velocity;
+            let max_change = self.acceleration * dt;
+            self.velocity += if change.magnitude() > max_change {
+                change.normalize_to(max_change)
+            } else {
+                change
+            };
+        }
+        camera.position += self.velocity * dt;
 
-        // move up/down
-        // since we dont use roll, we can just modify the y-coordinate directly
-        camera.position.y += (
-            self.amount_up - self.amount_down
-        ) * self.speed * dt;
+        // move in/out (zoom)
+        let scroll = take_smoothed(&mut self.scroll, self.scroll_smoothing, dt);
+        camera.position += camera.forward() * scroll * self.speed * self.sensitivity * PIXEL_SCALE;
 
         // rotate
-        camera.yaw += Rad(self.rotate_horizontal) * self.sensitivity * dt;
-        camera.pitch += Rad(-self.rotate_vertical) * self.sensitivity * dt;
-
-        // if process_mouse is not called each frame then these values will not
-        // be reset to zero, and the camera will rotate when moving in a  
-        // non-cardinal direction.
-        self.rotate_horizontal = 0.0;
-        self.rotate_vertical = 0.0;
+        let horizontal = take_smoothed(&mut self.rotate_horizontal, self.mouse_smoothing, dt);
+        let vertical = take_smoothed(&mut self.rotate_vertical, self.mouse_smoothing, dt);
+        camera.yaw += Rad(horizontal) * self.sensitivity * PIXEL_SCALE;
+        camera.pitch += Rad(-vertical) * self.sensitivity * PIXEL_SCALE;
 
         // stop the cameras angle from going to high/low
         if camera.pitch < -Rad(SAFE_FRAC_PI_2) {
@@ -232,7 +617,294 @@ impl CameraController {
 
 }
 
+// Rotates around a target point with the left mouse button, pans the target
+// with the middle button and zooms towards it with the scroll wheel.
+#[derive(Debug)]
+pub struct OrbitController {
+    pub target: Point3<f32>,
+    distance: f32,
+    yaw: Rad<f32>,
+    pitch: Rad<f32>,
+    rotate_horizontal: f32,
+    rotate_vertical: f32,
+    pan_horizontal: f32,
+    pan_vertical: f32,
+    scroll: f32,
+    sensitivity: f32,
+}
 
+impl OrbitController {
+    const MIN_DISTANCE: f32 = 0.1;
+    // fraction of the distance zoomed per pixel scrolled
+    const ZOOM_SPEED: f32 = 0.001;
+    // fraction of the distance panned per pixel of mouse movement
+    const PAN_SPEED: f32 = 0.002;
 
+    pub fn new<P: Into<Point3<f32>>>(target: P, distance: f32, sensitivity: f32) -> Self {
+        Self {
+            target: target.into(),
+            distance,
+            yaw: Rad(0.0),
+            pitch: Rad(0.0),
+            rotate_horizontal: 0.0,
+            rotate_vertical: 0.0,
+            pan_horizontal: 0.0,
+            pan_vertical: 0.0,
+            scroll: 0.0,
+            sensitivity,
+        }
+    }
+
+    // keeps the camera where it is, orbiting the point `distance` in front of it
+    pub fn look_from(&mut self, camera: &Camera) {
+        self.yaw = camera.yaw;
+        self.pitch = camera.pitch;
+        self.target = camera.position + camera.forward() * self.distance;
+    }
+}
+
+impl CameraControl for OrbitController {
+    fn process_axis(&mut self, _action: Action, _amount: f32) -> bool {
+        false
+    }
 
+    fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
+        self.rotate_horizontal += mouse_dx as f32;
+        self.rotate_vertical += mouse_dy as f32;
+    }
+
+    fn process_pan(&mut self, mouse_dx: f64, mouse_dy: f64) {
+        self.pan_horizontal += mouse_dx as f32;
+        self.pan_vertical += mouse_dy as f32;
+    }
 
+    fn process_scroll(&mut self, delta: &MouseScrollDelta) {
+        self.scroll += scroll_amount(delta);
+    }
+
+    fn focus_distance(&self) -> Option<f32> {
+        Some(self.distance)
+    }
+
+    fn update_camera(&mut self, camera: &mut Camera, _dt: Duration) {
+        // same rotation conventions as the fly camera, the deltas are
+        // already per frame
+        self.yaw += Rad(self.rotate_horizontal) * self.sensitivity * PIXEL_SCALE;
+        self.pitch += Rad(-self.rotate_vertical) * self.sensitivity * PIXEL_SCALE;
+        self.pitch = Rad(self.pitch.0.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2));
+        self.rotate_horizontal = 0.0;
+        self.rotate_vertical = 0.0;
+
+        self.distance = (
+            self.distance * (1.0 + self.scroll * Self::ZOOM_SPEED)
+        ).max(Self::MIN_DISTANCE);
+        self.scroll = 0.0;
+
+        camera.yaw = self.yaw;
+        camera.pitch = self.pitch;
+
+        // pan in the camera's view plane, faster the further out we are
+        let forward = camera.forward();
+        let right = forward.cross(Vector3::unit_y()).normalize();
+        let up = right.cross(forward);
+        let pan_scale = self.distance * Self::PAN_SPEED;
+        self.target += (-right * self.pan_horizontal + up * self.pan_vertical) * pan_scale;
+        self.pan_horizontal = 0.0;
+        self.pan_vertical = 0.0;
+
+        camera.position = self.target - forward * self.distance;
+    }
+}
+
+// Six degrees of freedom for flight and space views: the mouse turns around
+// the camera's own axes, the roll actions roll, and the move, jump and crouch
+// actions move relative to wherever the camera is facing. The camera it flies carries the
+// roll, the yaw/pitch camera it updates only follows along.
+#[derive(Debug)]
+pub struct FlightController {
+    camera: QuatCamera,
+    amount_left: f32,
+    amount_right: f32,
+    amount_forward: f32,
+    amount_backward: f32,
+    amount_up: f32,
+    amount_down: f32,
+    amount_roll_left: f32,
+    amount_roll_right: f32,
+    rotate_horizontal: f32,
+    rotate_vertical: f32,
+    scroll: f32,
+    pub speed: f32,
+    pub sensitivity: f32,
+    // radians per second
+    pub roll_speed: f32,
+}
+
+impl FlightController {
+    pub fn new(speed: f32, sensitivity: f32) -> Self {
+        Self {
+            camera: QuatCamera {
+                position: Point3::origin(),
+                orientation: Quaternion::one(),
+            },
+            amount_left: 0.0,
+            amount_right: 0.0,
+            amount_forward: 0.0,
+            amount_backward: 0.0,
+            amount_up: 0.0,
+            amount_down: 0.0,
+            amount_roll_left: 0.0,
+            amount_roll_right: 0.0,
+            rotate_horizontal: 0.0,
+            rotate_vertical: 0.0,
+            scroll: 0.0,
+            speed,
+            sensitivity,
+            roll_speed: 1.5,
+        }
+    }
+
+    // carries on from the camera, level with the horizon
+    pub fn look_from(&mut self, camera: &Camera) {
+        self.camera = QuatCamera::from(camera);
+    }
+
+    pub fn camera(&self) -> &QuatCamera {
+        &self.camera
+    }
+
+    pub fn set_camera(&mut self, camera: QuatCamera) {
+        self.camera = camera;
+    }
+}
+
+impl CameraControl for FlightController {
+    fn process_axis(&mut self, action: Action, amount: f32) -> bool {
+        let target = match action {
+            Action::MoveForward => &mut self.amount_forward,
+            Action::MoveBackward => &mut self.amount_backward,
+            Action::MoveLeft => &mut self.amount_left,
+            Action::MoveRight => &mut self.amount_right,
+            Action::Jump => &mut self.amount_up,
+            Action::Crouch => &mut self.amount_down,
+            Action::RollLeft => &mut self.amount_roll_left,
+            Action::RollRight => &mut self.amount_roll_right,
+            _ => return false,
+        };
+        *target = amount;
+        true
+    }
+
+    fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
+        self.rotate_horizontal += mouse_dx as f32;
+        self.rotate_vertical += mouse_dy as f32;
+    }
+
+    fn process_scroll(&mut self, delta: &MouseScrollDelta) {
+        self.scroll += scroll_amount(delta);
+    }
+
+    fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
+        let dt = dt.as_secs_f32();
+
+        // turning right is a negative rotation around up, same for pitching
+        // down around right
+        let scale = self.sensitivity * PIXEL_SCALE;
+        self.camera.rotate_local(
+            Rad(-self.rotate_horizontal * scale),
+            Rad(-self.rotate_vertical * scale),
+            Rad((self.amount_roll_left - self.amount_roll_right) * self.roll_speed * dt),
+        );
+        self.rotate_horizontal = 0.0;
+        self.rotate_vertical = 0.0;
+
+        let movement = self.camera.forward() * (self.amount_forward - self.amount_backward)
+            + self.camera.right() * (self.amount_right - self.amount_left)
+            + self.camera.up() * (self.amount_up - self.amount_down);
+        self.camera.position += movement * self.speed * dt;
+        self.camera.position += self.camera.forward() * self.scroll * self.speed * scale;
+        self.scroll = 0.0;
+
+        *camera = Camera::from(&self.camera);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn projection() -> Projection {
+        Projection::new(800, 600, Deg(45.0), 0.1, 100.0)
+    }
+
+    #[test]
+    fn lens_shift_round_trips() {
+        let mut projection = projection();
+        projection.toggle_lens_shift(0.25);
+        match projection.kind() {
+            ProjectionKind::OffAxis { left, right, bottom, top } => {
+                assert_eq!(left, -right);
+                assert!((top + bottom - (top - bottom) * 0.5).abs() < 1e-6);
+            }
+            kind => panic!("expected off-axis, got {:?}", kind),
+        }
+        projection.toggle_lens_shift(0.25);
+        match projection.kind() {
+            ProjectionKind::Perspective { fovy } => {
+                assert!((fovy.0 - Rad::from(Deg(45.0)).0).abs() < 1e-6)
+            }
+            kind => panic!("expected perspective, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn oblique_keeps_the_focus_plane() {
+        let mut projection = projection();
+        projection.toggle_orthographic(5.0);
+        let orthographic = projection.calc_matrix();
+        projection.toggle_orthographic(5.0);
+        projection.toggle_oblique(5.0);
+        assert!(matches!(projection.kind(), ProjectionKind::Custom(_)));
+        let oblique = projection.calc_matrix();
+
+        let on_plane = Vector4::new(1.0, 2.0, -5.0, 1.0);
+        let expected = orthographic * on_plane;
+        let actual = oblique * on_plane;
+        assert!((expected - actual).magnitude() < 1e-5);
+        // farther away slants up and to the right
+        let behind = oblique * Vector4::new(1.0, 2.0, -6.0, 1.0);
+        assert!(behind.x > actual.x && behind.y > actual.y);
+
+        projection.toggle_oblique(5.0);
+        assert!(matches!(projection.kind(), ProjectionKind::Perspective { .. }));
+    }
+
+    #[test]
+    fn resizing_stretches_off_axis_extents() {
+        let mut projection = projection();
+        projection.toggle_lens_shift(0.0);
+        projection.resize(1200, 600);
+        match projection.kind() {
+            ProjectionKind::OffAxis { right, top, .. } => {
+                assert!((right / top - 2.0).abs() < 1e-5)
+            }
+            kind => panic!("expected off-axis, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn orbiting_ignores_the_frame_time() {
+        let orbit = |dt| {
+            let mut controller = OrbitController::new((0.0, 0.0, 0.0), 10.0, 0.4);
+            let mut camera = Camera::new((0.0, 0.0, 10.0), Deg(-90.0), Deg(0.0));
+            controller.look_from(&camera);
+            controller.process_mouse(30.0, -20.0);
+            controller.update_camera(&mut camera, Duration::from_secs_f32(dt));
+            (camera.yaw, camera.pitch)
+        };
+        let (yaw, pitch) = orbit(1.0 / 30.0);
+        assert_eq!((yaw, pitch), orbit(1.0 / 144.0));
+        assert!((yaw.0 - (Rad::from(Deg(-90.0)).0 + 30.0 * 0.4 * PIXEL_SCALE)).abs() < 1e-6);
+        assert!((pitch.0 - 20.0 * 0.4 * PIXEL_SCALE).abs() < 1e-6);
+    }
+}
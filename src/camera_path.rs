@@ -0,0 +1,297 @@
+use std::io;
+use std::path::Path;
+
+use cgmath::*;
+use instant::Duration;
+
+use crate::camera::QuatCamera;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Keyframe {
+    // seconds since the start of the path
+    pub time: f32,
+    pub camera: QuatCamera,
+}
+
+// Keyframes of a camera flight in time order. Saved as plain text with one
+// keyframe per line: time, position x y z, orientation s x y z.
+#[derive(Debug, Default)]
+pub struct CameraPath {
+    keyframes: Vec<Keyframe>,
+}
+
+impl CameraPath {
+    // keyframes that don't come after the last one are dropped
+    pub fn push(&mut self, time: f32, camera: QuatCamera) {
+        if self.keyframes.last().is_none_or(|last| time > last.time) {
+            self.keyframes.push(Keyframe { time, camera });
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keyframes.is_empty()
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |last| last.time)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(path, self.format())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn format(&self) -> String {
+        self.keyframes.iter()
+            .map(|Keyframe { time, camera }| {
+                let p = camera.position;
+                let q = camera.orientation;
+                format!(
+                    "{} {} {} {} {} {} {} {}\n",
+                    time, p.x, p.y, p.z, q.s, q.v.x, q.v.y, q.v.z,
+                )
+            })
+            .collect()
+    }
+
+    fn parse(text: &str) -> io::Result<Self> {
+        let mut camera_path = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let invalid = || io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad camera path keyframe on line {}", number + 1),
+            );
+            let values = line.split_whitespace()
+                .map(str::parse::<f32>)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid())?;
+            let &[time, px, py, pz, s, x, y, z] = values.as_slice() else {
+                return Err(invalid());
+            };
+            camera_path.push(time, QuatCamera {
+                position: Point3::new(px, py, pz),
+                orientation: Quaternion::new(s, x, y, z).normalize(),
+            });
+        }
+        Ok(camera_path)
+    }
+
+    // Catmull-Rom through the positions, slerp between the orientations.
+    // Clamps to the ends of the path, None if it's empty.
+    pub fn sample(&self, time: f32) -> Option<QuatCamera> {
+        let keyframes = &self.keyframes;
+        let last = keyframes.len().checked_sub(1)?;
+        let next = keyframes.partition_point(|k| k.time <= time);
+        if next == 0 {
+            return Some(keyframes[0].camera);
+        }
+        if next > last {
+            return Some(keyframes[last].camera);
+        }
+
+        let (k1, k2) = (&keyframes[next - 1], &keyframes[next]);
+        let t = (time - k1.time) / (k2.time - k1.time);
+        // the end keyframes stand in for the missing neighbours
+        let p0 = keyframes[next.saturating_sub(2)].camera.position.to_vec();
+        let p1 = k1.camera.position.to_vec();
+        let p2 = k2.camera.position.to_vec();
+        let p3 = keyframes[(next + 1).min(last)].camera.position.to_vec();
+        let position = (
+            p1 * 2.0
+                + (p2 - p0) * t
+                + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * (t * t)
+                + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * (t * t * t)
+        ) * 0.5;
+
+        Some(QuatCamera {
+            position: Point3::from_vec(position),
+            orientation: k1.camera.orientation.slerp(k2.camera.orientation, t),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Idle,
+    Recording,
+    Playing,
+}
+
+// Records the camera into a path and plays it back. While playing it decides
+// where the camera is and the camera controllers are ignored.
+#[derive(Debug)]
+pub struct CameraPathPlayer {
+    pub path: CameraPath,
+    mode: Mode,
+    time: f32,
+    since_keyframe: f32,
+    // seconds between recorded keyframes, the spline fills in the rest
+    pub keyframe_interval: f32,
+}
+
+impl CameraPathPlayer {
+    pub fn new(keyframe_interval: f32) -> Self {
+        Self {
+            path: CameraPath::default(),
+            mode: Mode::Idle,
+            time: 0.0,
+            since_keyframe: 0.0,
+            keyframe_interval,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.mode == Mode::Recording
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.mode == Mode::Playing
+    }
+
+    // replaces the current path
+    pub fn start_recording(&mut self) {
+        self.path = CameraPath::default();
+        self.mode = Mode::Recording;
+        self.time = 0.0;
+        self.since_keyframe = 0.0;
+    }
+
+    pub fn start_playback(&mut self) {
+        if !self.path.is_empty() {
+            self.mode = Mode::Playing;
+            self.time = 0.0;
+        }
+    }
+
+    // keeps `camera` as the final keyframe
+    pub fn stop_recording(&mut self, camera: &QuatCamera) {
+        if self.mode == Mode::Recording {
+            self.path.push(self.time, *camera);
+            self.mode = Mode::Idle;
+        }
+    }
+
+    pub fn stop_playback(&mut self) {
+        if self.mode == Mode::Playing {
+            self.mode = Mode::Idle;
+        }
+    }
+
+    // while recording, keeps a keyframe of `camera` every keyframe_interval
+    pub fn record(&mut self, camera: &QuatCamera, dt: Duration) {
+        if self.mode != Mode::Recording {
+            return;
+        }
+        if self.path.is_empty() || self.since_keyframe >= self.keyframe_interval {
+            self.path.push(self.time, *camera);
+            self.since_keyframe = 0.0;
+        }
+        let dt = dt.as_secs_f32();
+        self.time += dt;
+        self.since_keyframe += dt;
+    }
+
+    // the camera to show while playing, stops at the end of the path
+    pub fn play(&mut self, dt: Duration) -> Option<QuatCamera> {
+        if self.mode != Mode::Playing {
+            return None;
+        }
+        self.time += dt.as_secs_f32();
+        if self.time >= self.path.duration() {
+            self.mode = Mode::Idle;
+        }
+        self.path.sample(self.time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(x: f32, yaw: f32) -> QuatCamera {
+        QuatCamera {
+            position: Point3::new(x, 0.0, 0.0),
+            orientation: Quaternion::from_angle_y(Deg(yaw)),
+        }
+    }
+
+    fn path(cameras: &[(f32, QuatCamera)]) -> CameraPath {
+        let mut path = CameraPath::default();
+        for &(time, camera) in cameras {
+            path.push(time, camera);
+        }
+        path
+    }
+
+    #[test]
+    fn sample_passes_through_keyframes() {
+        let path = path(&[
+            (0.0, camera(0.0, 0.0)),
+            (1.0, camera(1.0, 90.0)),
+            (2.0, camera(4.0, 180.0)),
+        ]);
+        for (time, x) in [(0.0, 0.0), (1.0, 1.0), (2.0, 4.0)] {
+            let sampled = path.sample(time).unwrap();
+            assert!((sampled.position.x - x).abs() < 1e-5, "at {}", time);
+        }
+        let halfway = path.sample(0.5).unwrap();
+        let expected = Quaternion::from_angle_y(Deg(45.0));
+        assert!(halfway.orientation.dot(expected).abs() > 1.0 - 1e-5);
+    }
+
+    #[test]
+    fn sample_is_linear_on_evenly_spaced_lines() {
+        // Catmull-Rom reproduces straight lines through evenly spaced points,
+        // away from the ends where the neighbours are made up
+        let path = path(&[
+            (0.0, camera(0.0, 0.0)),
+            (1.0, camera(1.0, 0.0)),
+            (2.0, camera(2.0, 0.0)),
+            (3.0, camera(3.0, 0.0)),
+        ]);
+        for time in [1.25, 1.5, 1.75] {
+            assert!((path.sample(time).unwrap().position.x - time).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn sample_clamps_to_the_ends() {
+        let path = path(&[(1.0, camera(1.0, 0.0)), (2.0, camera(2.0, 0.0))]);
+        assert_eq!(path.sample(0.0).unwrap().position.x, 1.0);
+        assert_eq!(path.sample(5.0).unwrap().position.x, 2.0);
+        assert!(CameraPath::default().sample(0.0).is_none());
+    }
+
+    #[test]
+    fn push_drops_keyframes_out_of_order() {
+        let path = path(&[
+            (1.0, camera(0.0, 0.0)),
+            (1.0, camera(1.0, 0.0)),
+            (0.5, camera(2.0, 0.0)),
+        ]);
+        assert_eq!(path.keyframes.len(), 1);
+    }
+
+    #[test]
+    fn format_and_parse_round_trip() {
+        let path = path(&[(0.0, camera(1.5, 30.0)), (0.25, camera(-2.0, 120.0))]);
+        let parsed = CameraPath::parse(&path.format()).unwrap();
+        assert_eq!(parsed.keyframes.len(), 2);
+        for (a, b) in path.keyframes.iter().zip(&parsed.keyframes) {
+            assert_eq!(a.time, b.time);
+            assert_eq!(a.camera.position, b.camera.position);
+            assert!(a.camera.orientation.dot(b.camera.orientation) > 1.0 - 1e-6);
+        }
+    }
+
+    #[test]
+    fn parse_rejects_bad_lines() {
+        let error = CameraPath::parse("0 0 0 0 1 0 0 0\n1 2 3\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 2"));
+        assert!(CameraPath::parse("0 0 0 0 1 0 0 x\n").is_err());
+    }
+}
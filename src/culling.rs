@@ -0,0 +1,332 @@
+use std::ops::Range;
+
+use cgmath::{InnerSpace, Matrix, Matrix4, Point3, Vector3, Vector4};
+
+use crate::lod::LodSelector;
+use crate::model::Model;
+use crate::InstanceRaw;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    // None if there are no points
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Self { min: first, max: first }, |aabb, p| Self {
+            min: Point3::new(aabb.min.x.min(p.x), aabb.min.y.min(p.y), aabb.min.z.min(p.z)),
+            max: Point3::new(aabb.max.x.max(p.x), aabb.max.y.max(p.y), aabb.max.z.max(p.z)),
+        }))
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        Point3::new(
+            (self.min.x + self.max.x) * 0.5,
+            (self.min.y + self.max.y) * 0.5,
+            (self.min.z + self.max.z) * 0.5,
+        )
+    }
+
+    pub fn half_extents(&self) -> Vector3<f32> {
+        (self.max - self.min) * 0.5
+    }
+
+    // the box enclosing this one after it has been transformed
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Self {
+        let center = self.center();
+        let extents = self.half_extents();
+        let center = matrix * center.to_homogeneous();
+        let center = Point3::new(center.x, center.y, center.z);
+        // each world axis extent is the sum of the projected local extents
+        let abs = |v: Vector4<f32>| Vector3::new(v.x.abs(), v.y.abs(), v.z.abs());
+        let extents = abs(matrix.x) * extents.x
+            + abs(matrix.y) * extents.y
+            + abs(matrix.z) * extents.z;
+        Self {
+            min: center - extents,
+            max: center + extents,
+        }
+    }
+}
+
+// planes are stored as (normal, distance) with normals pointing inwards
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    // extracts the planes from a wgpu style (0..1 depth) view projection
+    // matrix, as in Gribb & Hartmann. With reverse-Z the near and far planes
+    // swap places, an infinite far plane comes out as one every point is
+    // inside of.
+    pub fn from_matrix(view_proj: Matrix4<f32>) -> Self {
+        let row = |i| view_proj.row(i);
+        let normalize = |p: Vector4<f32>| {
+            let magnitude = p.truncate().magnitude();
+            if magnitude > f32::EPSILON {
+                p / magnitude
+            } else {
+                Vector4::unit_w()
+            }
+        };
+        Self {
+            planes: [
+                normalize(row(3) + row(0)), // left
+                normalize(row(3) - row(0)), // right
+                normalize(row(3) + row(1)), // bottom
+                normalize(row(3) - row(1)), // top
+                normalize(row(2)),          // near, far with reverse-Z
+                normalize(row(3) - row(2)), // far, near with reverse-Z
+            ],
+        }
+    }
+
+    pub fn planes(&self) -> &[Vector4<f32>; 6] {
+        &self.planes
+    }
+
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner furthest along the plane normal
+            let p = Vector3::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            plane.truncate().dot(p) + plane.w >= 0.0
+        })
+    }
+}
+
+// culled instances of a mesh LOD that share a material
+#[derive(Debug, Clone, PartialEq)]
+pub struct CulledBatch {
+    pub lod: usize,
+    // the instances' own or else the mesh's
+    pub material: usize,
+    pub instances: Range<u32>,
+}
+
+// Tests every instance of every mesh against the view frustum and packs the
+// survivors into an instance buffer, one contiguous range per mesh LOD and
+// material.
+pub struct InstanceCuller {
+    instance_buffer: wgpu::Buffer,
+    capacity: usize,
+    // indexed by mesh, by LOD then material, the batches of a mesh are
+    // contiguous
+    pub batches: Vec<Vec<CulledBatch>>,
+    // indexed by mesh
+    pub visible: Vec<Vec<usize>>,
+}
+
+impl InstanceCuller {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            instance_buffer: Self::create_instance_buffer(device, 1),
+            capacity: 1,
+            batches: Vec::new(),
+            visible: Vec::new(),
+        }
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Culled Instance Buffer"),
+            size: (capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn instance_buffer(&self) -> &wgpu::Buffer {
+        &self.instance_buffer
+    }
+
+    // every visible instance of a mesh, across all its LODs
+    pub fn mesh_range(&self, mesh: usize) -> Range<u32> {
+        let batches = &self.batches[mesh];
+        match (batches.first(), batches.last()) {
+            (Some(first), Some(last)) => first.instances.start..last.instances.end,
+            _ => 0..0,
+        }
+    }
+
+    pub fn cull(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        frustum: &Frustum,
+        instances: &[InstanceRaw],
+        model: &Model,
+        lods: &LodSelector,
+    ) {
+        self.batches.clear();
+        self.visible.clear();
+
+        let material_count = model.materials.len();
+        let mut culled_data = Vec::new();
+        for (mesh_index, mesh) in model.meshes.iter().enumerate() {
+            let visible = instances
+                .iter()
+                .enumerate()
+                .filter(|(_, raw)| {
+                    frustum.intersects_aabb(&mesh.bounds.transform(&raw.model.into()))
+                })
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+
+            self.batches.push(pack_mesh(
+                &mut culled_data,
+                instances,
+                &visible,
+                mesh.lods.len(),
+                |i| lods.lod(mesh_index, i),
+                mesh.material,
+                material_count,
+            ));
+            self.visible.push(visible);
+        }
+
+        if culled_data.is_empty() {
+            return;
+        }
+        if culled_data.len() > self.capacity {
+            self.capacity = culled_data.len().next_power_of_two();
+            self.instance_buffer = Self::create_instance_buffer(device, self.capacity);
+        }
+        queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&culled_data));
+    }
+}
+
+// Appends a mesh's visible instances to `culled`, a batch per LOD and
+// material. Each copy has the material it's drawn with filled in, so the
+// material array shader doesn't need to know which mesh it's drawing.
+fn pack_mesh(
+    culled: &mut Vec<InstanceRaw>,
+    instances: &[InstanceRaw],
+    visible: &[usize],
+    lod_count: usize,
+    lod: impl Fn(usize) -> usize,
+    mesh_material: usize,
+    material_count: usize,
+) -> Vec<CulledBatch> {
+    let material = |i: usize| instances[i].material_for(mesh_material, material_count);
+    let mut batches = Vec::new();
+    for batch_lod in 0..lod_count {
+        let mut lod_visible = visible.iter()
+            .copied()
+            .filter(|&i| lod(i) == batch_lod)
+            .collect::<Vec<_>>();
+        lod_visible.sort_by_key(|&i| material(i));
+        for same_material in lod_visible.chunk_by(|&a, &b| material(a) == material(b)) {
+            let start = culled.len() as u32;
+            let batch_material = material(same_material[0]);
+            culled.extend(same_material.iter().map(|&i| InstanceRaw {
+                material: batch_material as u32,
+                ..instances[i]
+            }));
+            batches.push(CulledBatch {
+                lod: batch_lod,
+                material: batch_material,
+                instances: start..culled.len() as u32,
+            });
+        }
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Deg;
+
+    use super::*;
+    use crate::camera::Projection;
+
+    fn aabb(center: [f32; 3], half_extent: f32) -> Aabb {
+        let center = Point3::from(center);
+        let extents = Vector3::new(half_extent, half_extent, half_extent);
+        Aabb {
+            min: center - extents,
+            max: center + extents,
+        }
+    }
+
+    // at the origin looking down -z
+    fn frustum() -> Frustum {
+        let projection = Projection::new(1, 1, Deg(90.0), 0.1, 100.0);
+        Frustum::from_matrix(projection.calc_matrix())
+    }
+
+    #[test]
+    fn boxes_in_view_intersect() {
+        let frustum = frustum();
+        assert!(frustum.intersects_aabb(&aabb([0.0, 0.0, -5.0], 1.0)));
+        // mostly off the left edge, which is at x = z with a 90 degree view
+        assert!(frustum.intersects_aabb(&aabb([-5.5, 0.0, -5.0], 1.0)));
+        // straddling the near plane
+        assert!(frustum.intersects_aabb(&aabb([0.0, 0.0, 0.0], 0.5)));
+    }
+
+    #[test]
+    fn boxes_out_of_view_dont() {
+        let frustum = frustum();
+        assert!(!frustum.intersects_aabb(&aabb([0.0, 0.0, 5.0], 1.0)));
+        assert!(!frustum.intersects_aabb(&aabb([-7.5, 0.0, -5.0], 1.0)));
+        assert!(!frustum.intersects_aabb(&aabb([0.0, 7.5, -5.0], 1.0)));
+        assert!(!frustum.intersects_aabb(&aabb([0.0, -7.5, -5.0], 1.0)));
+    }
+
+    #[test]
+    fn far_plane_culls_without_reverse_z() {
+        let far = aabb([0.0, 0.0, -200.0], 1.0);
+        // reverse-Z puts the far plane at infinity
+        assert_eq!(
+            frustum().intersects_aabb(&far),
+            crate::texture::Texture::REVERSE_Z,
+        );
+    }
+
+    #[test]
+    fn packed_instances_carry_the_material_they_are_drawn_with() {
+        let instances = [None, Some(2), Some(9), Some(0), None]
+            .map(|material| crate::Instance {
+                material,
+                ..Default::default()
+            }.to_raw());
+        // instance 1 is culled, instance 4 drops to LOD 1
+        let mut culled = vec![instances[1]];
+        let batches = pack_mesh(
+            &mut culled,
+            &instances,
+            &[0, 2, 3, 4],
+            2,
+            |i| if i == 4 { 1 } else { 0 },
+            1,
+            3,
+        );
+        let batch = |lod, material, instances| CulledBatch { lod, material, instances };
+        assert_eq!(batches, [batch(0, 0, 1..2), batch(0, 1, 2..4), batch(1, 1, 4..5)]);
+        // the mesh's own material stands in for none or one that doesn't exist
+        let materials = culled[1..].iter().map(|raw| raw.material).collect::<Vec<_>>();
+        assert_eq!(materials, [0, 1, 1, 1]);
+        // packing appends after what's already there
+        assert_eq!(culled[0].material, 2);
+    }
+
+    #[test]
+    fn transformed_boxes_enclose_the_transformed_corners() {
+        let matrix = Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0))
+            * Matrix4::from_angle_z(Deg(45.0))
+            * Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0);
+        let transformed = aabb([0.0; 3], 1.0).transform(&matrix);
+        let half = 1.5 * 2.0f32.sqrt();
+        assert!((transformed.min - Point3::new(1.0 - half, 2.0 - half, 2.0)).magnitude() < 1e-5);
+        assert!((transformed.max - Point3::new(1.0 + half, 2.0 + half, 4.0)).magnitude() < 1e-5);
+    }
+}
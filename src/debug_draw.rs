@@ -0,0 +1,297 @@
+use std::f32::consts::TAU;
+
+use cgmath::{Matrix4, Point3, Vector3, Vector4};
+
+use crate::model::Vertex;
+use crate::{texture, PipelineOptions};
+
+// segments used per circle when approximating spheres
+const SPHERE_SEGMENTS: usize = 24;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DebugVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+}
+
+impl Vertex for DebugVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<DebugVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x3,
+                    offset: 0,
+                    shader_location: 0,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x3,
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                },
+            ],
+        }
+    }
+}
+
+type Segment = (Point3<f32>, Point3<f32>);
+
+// the 12 edges of a box
+fn aabb_edges(min: Point3<f32>, max: Point3<f32>) -> Vec<Segment> {
+    let corner = |i: usize| Point3::new(
+        if i & 1 == 0 { min.x } else { max.x },
+        if i & 2 == 0 { min.y } else { max.y },
+        if i & 4 == 0 { min.z } else { max.z },
+    );
+    // each edge joins two corners that differ in exactly one axis bit
+    (0..8)
+        .flat_map(|i| [1, 2, 4].into_iter().map(move |bit| (i, bit)))
+        .filter(|&(i, bit)| i & bit == 0)
+        .map(|(i, bit)| (corner(i), corner(i | bit)))
+        .collect()
+}
+
+// three axis aligned great circles
+fn sphere_segments(center: Point3<f32>, radius: f32) -> Vec<Segment> {
+    let point = |axis: usize, i: usize| {
+        let (sin, cos) = (i as f32 / SPHERE_SEGMENTS as f32 * TAU).sin_cos();
+        let (a, b) = (cos * radius, sin * radius);
+        center + match axis {
+            0 => Vector3::new(0.0, a, b),
+            1 => Vector3::new(a, 0.0, b),
+            _ => Vector3::new(a, b, 0.0),
+        }
+    };
+    (0..3)
+        .flat_map(|axis| (0..SPHERE_SEGMENTS).map(move |i| (axis, i)))
+        .map(|(axis, i)| (point(axis, i), point(axis, i + 1)))
+        .collect()
+}
+
+// unit length x/y/z axes in red/green/blue, moved by the transform
+fn axis_lines(transform: Matrix4<f32>) -> [(Point3<f32>, Point3<f32>, [f32; 3]); 3] {
+    let origin = Point3::from_homogeneous(transform * Vector4::unit_w());
+    [
+        (Vector3::unit_x(), [1.0, 0.0, 0.0]),
+        (Vector3::unit_y(), [0.0, 1.0, 0.0]),
+        (Vector3::unit_z(), [0.0, 0.0, 1.0]),
+    ]
+    .map(|(axis, color)| {
+        (origin, Point3::from_homogeneous(transform * axis.extend(1.0)), color)
+    })
+}
+
+// Immediate mode line drawing for debugging. Shapes are queued up during
+// the frame, uploaded into a single vertex buffer and drawn as a line list,
+// then thrown away once drawn.
+pub struct DebugDraw {
+    pub enabled: bool,
+    // whether shapes queued from now on are hidden behind scene geometry
+    pub depth_test: bool,
+    depth_tested: Vec<DebugVertex>,
+    overlay: Vec<DebugVertex>,
+    vertex_buffer: wgpu::Buffer,
+    capacity: usize,
+    num_depth_tested: u32,
+    num_overlay: u32,
+    depth_tested_pipeline: wgpu::RenderPipeline,
+    overlay_pipeline: wgpu::RenderPipeline,
+}
+
+impl DebugDraw {
+    const INITIAL_CAPACITY: usize = 1024;
+
+    pub fn new(
+        device: &wgpu::Device,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+    ) -> Self {
+        let layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Debug Draw Pipeline Layout"),
+                bind_group_layouts: &[camera_bind_group_layout],
+                push_constant_ranges: &[],
+            }
+        );
+
+        let create_pipeline = |depth_compare| {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: Some("Debug Draw Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("debug_draw.wgsl").into()),
+            };
+            crate::create_render_pipeline_with_options(
+                device,
+                shader,
+                &layout,
+                &[DebugVertex::desc()],
+                color_format,
+                Some(texture::Texture::DEPTH_FORMAT),
+                PipelineOptions {
+                    topology: wgpu::PrimitiveTopology::LineList,
+                    depth_write_enabled: false,
+                    depth_compare,
+                    ..Default::default()
+                },
+            )
+        };
+
+        Self {
+            enabled: true,
+            depth_test: true,
+            depth_tested: Vec::new(),
+            overlay: Vec::new(),
+            vertex_buffer: Self::create_vertex_buffer(device, Self::INITIAL_CAPACITY),
+            capacity: Self::INITIAL_CAPACITY,
+            num_depth_tested: 0,
+            num_overlay: 0,
+            depth_tested_pipeline: create_pipeline(texture::Texture::DEPTH_COMPARE_EQUAL),
+            overlay_pipeline: create_pipeline(wgpu::CompareFunction::Always),
+        }
+    }
+
+    fn create_vertex_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Debug Draw Vertex Buffer"),
+            size: (capacity * std::mem::size_of::<DebugVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn line<P: Into<Point3<f32>>>(&mut self, a: P, b: P, color: [f32; 3]) {
+        if !self.enabled {
+            return;
+        }
+        let vertices = if self.depth_test {
+            &mut self.depth_tested
+        } else {
+            &mut self.overlay
+        };
+        vertices.push(DebugVertex { position: a.into().into(), color });
+        vertices.push(DebugVertex { position: b.into().into(), color });
+    }
+
+    pub fn aabb<P: Into<Point3<f32>>>(&mut self, min: P, max: P, color: [f32; 3]) {
+        for (a, b) in aabb_edges(min.into(), max.into()) {
+            self.line(a, b, color);
+        }
+    }
+
+    pub fn sphere<P: Into<Point3<f32>>>(&mut self, center: P, radius: f32, color: [f32; 3]) {
+        for (a, b) in sphere_segments(center.into(), radius) {
+            self.line(a, b, color);
+        }
+    }
+
+    // unit length x/y/z axes in red/green/blue, moved by the transform
+    pub fn axes(&mut self, transform: Matrix4<f32>) {
+        for (a, b, color) in axis_lines(transform) {
+            self.line(a, b, color);
+        }
+    }
+
+    // uploads everything queued this frame, growing the buffer if needed
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let needed = self.depth_tested.len() + self.overlay.len();
+        if needed > self.capacity {
+            self.capacity = needed.next_power_of_two();
+            self.vertex_buffer = Self::create_vertex_buffer(device, self.capacity);
+        }
+
+        self.num_depth_tested = self.depth_tested.len() as u32;
+        self.num_overlay = self.overlay.len() as u32;
+        self.depth_tested.append(&mut self.overlay);
+        if !self.depth_tested.is_empty() {
+            queue.write_buffer(
+                &self.vertex_buffer,
+                0,
+                bytemuck::cast_slice(&self.depth_tested),
+            );
+        }
+        self.depth_tested.clear();
+    }
+
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+    ) {
+        if self.num_depth_tested + self.num_overlay == 0 {
+            return;
+        }
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        if self.num_depth_tested > 0 {
+            render_pass.set_pipeline(&self.depth_tested_pipeline);
+            render_pass.draw(0..self.num_depth_tested, 0..1);
+        }
+        if self.num_overlay > 0 {
+            render_pass.set_pipeline(&self.overlay_pipeline);
+            render_pass.draw(
+                self.num_depth_tested..self.num_depth_tested + self.num_overlay,
+                0..1,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, InnerSpace, MetricSpace};
+
+    use super::*;
+
+    #[test]
+    fn box_edges_run_along_one_axis_each() {
+        let min = Point3::new(-1.0, -2.0, -3.0);
+        let max = Point3::new(1.0, 2.0, 3.0);
+        let edges = aabb_edges(min, max);
+        assert_eq!(edges.len(), 12);
+        let mut lengths = [0; 3];
+        for (a, b) in &edges {
+            let changed = (0..3).filter(|&axis| a[axis] != b[axis]).collect::<Vec<_>>();
+            assert_eq!(changed.len(), 1, "{:?} to {:?}", a, b);
+            lengths[changed[0]] += 1;
+            // corners only
+            for axis in 0..3 {
+                assert!(a[axis] == min[axis] || a[axis] == max[axis]);
+            }
+        }
+        // four edges along each axis
+        assert_eq!(lengths, [4, 4, 4]);
+    }
+
+    #[test]
+    fn sphere_circles_are_closed_and_on_the_surface() {
+        let center = Point3::new(1.0, 2.0, 3.0);
+        let segments = sphere_segments(center, 2.0);
+        assert_eq!(segments.len(), 3 * SPHERE_SEGMENTS);
+        for circle in segments.chunks(SPHERE_SEGMENTS) {
+            for pair in circle.windows(2) {
+                assert_eq!(pair[0].1, pair[1].0);
+            }
+            let (first, last) = (circle[0].0, circle[SPHERE_SEGMENTS - 1].1);
+            assert!(first.distance(last) < 1e-5);
+        }
+        for (a, _) in segments {
+            assert!((a.distance(center) - 2.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn axes_follow_the_transform() {
+        let transform = Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0))
+            * Matrix4::from_angle_z(Deg(90.0));
+        let [x, y, z] = axis_lines(transform);
+        for (a, _, _) in [x, y, z] {
+            assert_eq!(a, Point3::new(1.0, 0.0, 0.0));
+        }
+        assert_eq!(x.2, [1.0, 0.0, 0.0]);
+        assert!(((x.1 - x.0) - Vector3::unit_y()).magnitude() < 1e-6);
+        assert!(((y.1 - y.0) + Vector3::unit_x()).magnitude() < 1e-6);
+        assert!(((z.1 - z.0) - Vector3::unit_z()).magnitude() < 1e-6);
+    }
+}
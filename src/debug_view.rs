@@ -0,0 +1,154 @@
+use tracing::{info, warn};
+
+use crate::input::Action;
+use crate::model::{self, Vertex};
+use crate::{texture, InstanceRaw, PipelineOptions};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    Shaded,
+    Wireframe,
+    Normals,
+    Tangents,
+    Bitangents,
+    UvChecker,
+    Depth,
+}
+
+impl RenderMode {
+    // the render_* actions select the modes
+    pub fn from_action(action: Action) -> Option<Self> {
+        match action {
+            Action::RenderShaded => Some(Self::Shaded),
+            Action::RenderWireframe => Some(Self::Wireframe),
+            Action::RenderNormals => Some(Self::Normals),
+            Action::RenderTangents => Some(Self::Tangents),
+            Action::RenderBitangents => Some(Self::Bitangents),
+            Action::RenderUvChecker => Some(Self::UvChecker),
+            Action::RenderDepth => Some(Self::Depth),
+            _ => None,
+        }
+    }
+
+    fn fs_entry_point(&self) -> &'static str {
+        match self {
+            Self::Shaded => "fs_main",
+            Self::Wireframe => "fs_wireframe",
+            Self::Normals => "fs_normal",
+            Self::Tangents => "fs_tangent",
+            Self::Bitangents => "fs_bitangent",
+            Self::UvChecker => "fs_uv_checker",
+            Self::Depth => "fs_depth",
+        }
+    }
+}
+
+// how the wireframe mode gets its lines on screen
+pub enum Wireframe {
+    // the adapter rasterises triangle edges for us
+    PolygonMode,
+    // no PolygonMode::Line, draw the mesh edge index buffers as a line list
+    EdgeList,
+}
+
+pub struct DebugView {
+    pub mode: RenderMode,
+    pub wireframe: Wireframe,
+    wireframe_pipeline: wgpu::RenderPipeline,
+    normals_pipeline: wgpu::RenderPipeline,
+    tangents_pipeline: wgpu::RenderPipeline,
+    bitangents_pipeline: wgpu::RenderPipeline,
+    uv_checker_pipeline: wgpu::RenderPipeline,
+    depth_pipeline: wgpu::RenderPipeline,
+}
+
+impl DebugView {
+    // features the debug view would like, intersect with the adapter's
+    // features before requesting the device
+    pub const FEATURES: wgpu::Features = wgpu::Features::POLYGON_MODE_LINE;
+
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        color_format: wgpu::TextureFormat,
+    ) -> Self {
+        let wireframe = if device.features().contains(wgpu::Features::POLYGON_MODE_LINE) {
+            Wireframe::PolygonMode
+        } else {
+            warn!("PolygonMode::Line unsupported, falling back to edge lists for wireframe");
+            Wireframe::EdgeList
+        };
+
+        let create_pipeline = |mode: RenderMode| {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: Some("Debug View Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("debug_view.wgsl").into()),
+            };
+            let (topology, polygon_mode) = match (mode, &wireframe) {
+                (RenderMode::Wireframe, Wireframe::PolygonMode) => (
+                    wgpu::PrimitiveTopology::TriangleList,
+                    wgpu::PolygonMode::Line,
+                ),
+                (RenderMode::Wireframe, Wireframe::EdgeList) => (
+                    wgpu::PrimitiveTopology::LineList,
+                    wgpu::PolygonMode::Fill,
+                ),
+                _ => (
+                    wgpu::PrimitiveTopology::TriangleList,
+                    wgpu::PolygonMode::Fill,
+                ),
+            };
+            crate::create_render_pipeline_with_options(
+                device,
+                shader,
+                layout,
+                &[model::ModelVertex::desc(), InstanceRaw::desc()],
+                color_format,
+                Some(texture::Texture::DEPTH_FORMAT),
+                PipelineOptions {
+                    fs_entry_point: mode.fs_entry_point(),
+                    topology,
+                    polygon_mode,
+                    ..Default::default()
+                },
+            )
+        };
+
+        Self {
+            mode: RenderMode::Shaded,
+            wireframe_pipeline: create_pipeline(RenderMode::Wireframe),
+            normals_pipeline: create_pipeline(RenderMode::Normals),
+            tangents_pipeline: create_pipeline(RenderMode::Tangents),
+            bitangents_pipeline: create_pipeline(RenderMode::Bitangents),
+            uv_checker_pipeline: create_pipeline(RenderMode::UvChecker),
+            depth_pipeline: create_pipeline(RenderMode::Depth),
+            wireframe,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: RenderMode) {
+        if self.mode != mode {
+            info!("render mode: {:?}", mode);
+            self.mode = mode;
+        }
+    }
+
+    // the pipeline to draw the scene with, None means the regular shaded
+    // pipeline should be used
+    pub fn pipeline(&self) -> Option<&wgpu::RenderPipeline> {
+        match self.mode {
+            RenderMode::Shaded => None,
+            RenderMode::Wireframe => Some(&self.wireframe_pipeline),
+            RenderMode::Normals => Some(&self.normals_pipeline),
+            RenderMode::Tangents => Some(&self.tangents_pipeline),
+            RenderMode::Bitangents => Some(&self.bitangents_pipeline),
+            RenderMode::UvChecker => Some(&self.uv_checker_pipeline),
+            RenderMode::Depth => Some(&self.depth_pipeline),
+        }
+    }
+
+    // true when the scene has to be drawn from the edge index buffers
+    pub fn draws_edges(&self) -> bool {
+        self.mode == RenderMode::Wireframe && matches!(self.wireframe, Wireframe::EdgeList)
+    }
+}
@@ -0,0 +1,129 @@
+// Vertex shader
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(3) tangent: vec3<f32>,
+    @location(4) bitangent: vec3<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) world_normal: vec3<f32>,
+    @location(3) world_tangent: vec3<f32>,
+    @location(4) world_bitangent: vec3<f32>,
+};
+
+// the part of `v` at right angles to the unit vector `n`, normalised
+fn orthogonalise(v: vec3<f32>, n: vec3<f32>) -> vec3<f32> {
+    return normalize(v - dot(v, n) * n);
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * world_position;
+    out.tex_coords = model.tex_coords;
+    out.world_position = world_position.xyz;
+    // the same frame the lit shader builds
+    let model_linear = mat3x3<f32>(
+        model_matrix[0].xyz,
+        model_matrix[1].xyz,
+        model_matrix[2].xyz,
+    );
+    out.world_normal = normalize(normal_matrix * model.normal);
+    out.world_tangent = orthogonalise(model_linear * model.tangent, out.world_normal);
+    out.world_bitangent = orthogonalise(
+        orthogonalise(model_linear * model.bitangent, out.world_normal),
+        out.world_tangent,
+    );
+    return out;
+}
+
+// Fragment shaders
+
+// maps a unit vector from [-1, 1] into displayable [0, 1] colours
+fn vector_to_colour(v: vec3<f32>) -> vec4<f32> {
+    return vec4<f32>(normalize(v) * 0.5 + 0.5, 1.0);
+}
+
+@fragment
+fn fs_wireframe(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(0.9, 0.9, 0.9, 1.0);
+}
+
+@fragment
+fn fs_normal(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vector_to_colour(in.world_normal);
+}
+
+@fragment
+fn fs_tangent(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vector_to_colour(in.world_tangent);
+}
+
+@fragment
+fn fs_bitangent(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vector_to_colour(in.world_bitangent);
+}
+
+@fragment
+fn fs_uv_checker(in: VertexOutput) -> @location(0) vec4<f32> {
+    let squares = 8.0;
+    let cell = floor(in.tex_coords * squares);
+    let checker = (cell.x + cell.y) % 2.0;
+    // tint by uv so flipped or mirrored mappings stand out
+    let tint = vec3<f32>(fract(in.tex_coords), 1.0);
+    return vec4<f32>(mix(0.2, 1.0, checker) * tint, 1.0);
+}
+
+// distance that maps to white, a fixed range for visualising rather than
+// the far plane, which can be at infinity
+let MAX_DEPTH: f32 = 100.0;
+
+@fragment
+fn fs_depth(in: VertexOutput) -> @location(0) vec4<f32> {
+    let depth = clamp(
+        distance(in.world_position, camera.view_pos.xyz) / MAX_DEPTH,
+        0.0,
+        1.0
+    );
+    return vec4<f32>(vec3<f32>(depth), 1.0);
+}
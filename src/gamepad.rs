@@ -0,0 +1,390 @@
+use tracing::info;
+
+use crate::input::{GamepadAnalog, GamepadButton, InputEvent};
+
+// sticks go from -1 to 1 with up and right positive, triggers from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GamepadAxis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GamepadEvent {
+    Connected,
+    Disconnected,
+    Button(GamepadButton, bool),
+    Axis(GamepadAxis, f32),
+}
+
+// Where gamepad events come from, GilrsGamepad over the OS gamepad API or
+// a mock driven by hand in the tests.
+pub trait GamepadDevice {
+    fn poll(&mut self) -> Option<GamepadEvent>;
+}
+
+// Gamepads through gilrs. Only one is followed at a time, the first to
+// report anything, until it's disconnected.
+#[cfg(feature = "gilrs")]
+pub struct GilrsGamepad {
+    gilrs: gilrs::Gilrs,
+    active: Option<gilrs::GamepadId>,
+}
+
+#[cfg(feature = "gilrs")]
+impl GilrsGamepad {
+    pub fn new() -> Result<Self, Box<gilrs::Error>> {
+        Ok(Self {
+            gilrs: gilrs::Gilrs::new().map_err(Box::new)?,
+            active: None,
+        })
+    }
+
+    fn button(button: gilrs::Button) -> Option<GamepadButton> {
+        use gilrs::Button::*;
+        Some(match button {
+            South => GamepadButton::South,
+            East => GamepadButton::East,
+            West => GamepadButton::West,
+            North => GamepadButton::North,
+            LeftTrigger => GamepadButton::LeftBumper,
+            RightTrigger => GamepadButton::RightBumper,
+            Select => GamepadButton::Select,
+            Start => GamepadButton::Start,
+            LeftThumb => GamepadButton::LeftStick,
+            RightThumb => GamepadButton::RightStick,
+            DPadUp => GamepadButton::DPadUp,
+            DPadDown => GamepadButton::DPadDown,
+            DPadLeft => GamepadButton::DPadLeft,
+            DPadRight => GamepadButton::DPadRight,
+            _ => return None,
+        })
+    }
+
+    fn axis(axis: gilrs::Axis) -> Option<GamepadAxis> {
+        match axis {
+            gilrs::Axis::LeftStickX => Some(GamepadAxis::LeftStickX),
+            gilrs::Axis::LeftStickY => Some(GamepadAxis::LeftStickY),
+            gilrs::Axis::RightStickX => Some(GamepadAxis::RightStickX),
+            gilrs::Axis::RightStickY => Some(GamepadAxis::RightStickY),
+            _ => None,
+        }
+    }
+
+    fn event(event: gilrs::EventType) -> Option<GamepadEvent> {
+        use gilrs::EventType;
+        match event {
+            EventType::Connected => Some(GamepadEvent::Connected),
+            EventType::Disconnected => Some(GamepadEvent::Disconnected),
+            EventType::ButtonPressed(button, _) => {
+                Some(GamepadEvent::Button(Self::button(button)?, true))
+            }
+            EventType::ButtonReleased(button, _) => {
+                Some(GamepadEvent::Button(Self::button(button)?, false))
+            }
+            // the analog triggers, their digital presses come through above
+            EventType::ButtonChanged(gilrs::Button::LeftTrigger2, value, _) => {
+                Some(GamepadEvent::Axis(GamepadAxis::LeftTrigger, value))
+            }
+            EventType::ButtonChanged(gilrs::Button::RightTrigger2, value, _) => {
+                Some(GamepadEvent::Axis(GamepadAxis::RightTrigger, value))
+            }
+            EventType::AxisChanged(axis, value, _) => {
+                Some(GamepadEvent::Axis(Self::axis(axis)?, value))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "gilrs")]
+impl GamepadDevice for GilrsGamepad {
+    fn poll(&mut self) -> Option<GamepadEvent> {
+        while let Some(gilrs::Event { id, event, .. }) = self.gilrs.next_event() {
+            if *self.active.get_or_insert(id) != id {
+                continue;
+            }
+            if event == gilrs::EventType::Disconnected {
+                self.active = None;
+            }
+            if let Some(event) = Self::event(event) {
+                return Some(event);
+            }
+        }
+        None
+    }
+}
+
+// Turns raw stick and trigger positions into camera input. Positions inside
+// the dead zone count as zero and the rest of the range is stretched to
+// start from there, so there's no jump past its edge. The curve then raises
+// the result to a power, above 1 for finer control near the centre.
+pub struct Gamepad {
+    device: Box<dyn GamepadDevice>,
+    left_stick: [f32; 2],
+    right_stick: [f32; 2],
+    left_trigger: f32,
+    right_trigger: f32,
+    // last amounts sent for GamepadAnalog::ALL, only changes are passed on
+    analog: [f32; 6],
+    // fractions of the full range
+    pub stick_dead_zone: f32,
+    pub trigger_dead_zone: f32,
+    pub curve: f32,
+    // pixels of mouse movement a fully pushed right stick is worth per
+    // second
+    pub look_speed: f32,
+}
+
+impl Gamepad {
+    pub fn new(device: Box<dyn GamepadDevice>) -> Self {
+        Self {
+            device,
+            left_stick: [0.0; 2],
+            right_stick: [0.0; 2],
+            left_trigger: 0.0,
+            right_trigger: 0.0,
+            analog: [0.0; 6],
+            stick_dead_zone: 0.15,
+            trigger_dead_zone: 0.05,
+            curve: 2.0,
+            look_speed: 600.0,
+        }
+    }
+
+    // drains the device's events into what they did since the last poll,
+    // buttons and the sticks and triggers that moved
+    pub fn poll(&mut self) -> Vec<InputEvent> {
+        let mut inputs = Vec::new();
+        while let Some(event) = self.device.poll() {
+            match event {
+                GamepadEvent::Connected => info!("gamepad connected"),
+                GamepadEvent::Disconnected => {
+                    info!("gamepad disconnected");
+                    // let go of everything it was holding
+                    self.left_stick = [0.0; 2];
+                    self.right_stick = [0.0; 2];
+                    self.left_trigger = 0.0;
+                    self.right_trigger = 0.0;
+                }
+                GamepadEvent::Button(button, pressed) => {
+                    inputs.push(InputEvent::GamepadButton(button, pressed));
+                }
+                GamepadEvent::Axis(axis, value) => match axis {
+                    GamepadAxis::LeftStickX => self.left_stick[0] = value,
+                    GamepadAxis::LeftStickY => self.left_stick[1] = value,
+                    GamepadAxis::RightStickX => self.right_stick[0] = value,
+                    GamepadAxis::RightStickY => self.right_stick[1] = value,
+                    GamepadAxis::LeftTrigger => self.left_trigger = value,
+                    GamepadAxis::RightTrigger => self.right_trigger = value,
+                },
+            }
+        }
+
+        let [x, y] = self.shape_stick(self.left_stick);
+        let left = self.shape(self.left_trigger, self.trigger_dead_zone);
+        let right = self.shape(self.right_trigger, self.trigger_dead_zone);
+        let amounts = [y.max(0.0), (-y).max(0.0), (-x).max(0.0), x.max(0.0), left, right];
+        for ((analog, amount), sent) in GamepadAnalog::ALL.into_iter()
+            .zip(amounts)
+            .zip(&mut self.analog)
+        {
+            if amount != *sent {
+                *sent = amount;
+                inputs.push(InputEvent::GamepadAnalog(analog, amount));
+            }
+        }
+        inputs
+    }
+
+    // the right stick as mouse movement over `dt` seconds, down and right
+    // positive like the mouse
+    pub fn look(&self, dt: f32) -> (f64, f64) {
+        let [x, y] = self.shape_stick(self.right_stick);
+        let scale = self.look_speed * dt;
+        ((x * scale) as f64, (-y * scale) as f64)
+    }
+
+    // from 0 to 1
+    fn shape(&self, value: f32, dead_zone: f32) -> f32 {
+        let value = value.abs().min(1.0);
+        if value <= dead_zone {
+            return 0.0;
+        }
+        ((value - dead_zone) / (1.0 - dead_zone)).powf(self.curve)
+    }
+
+    // shaped by how far the stick is pushed in any direction, so the dead
+    // zone is round and diagonals aren't slowed down
+    fn shape_stick(&self, [x, y]: [f32; 2]) -> [f32; 2] {
+        let length = x.hypot(y);
+        if length == 0.0 {
+            return [0.0; 2];
+        }
+        let scale = self.shape(length, self.stick_dead_zone) / length;
+        [x * scale, y * scale]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use super::*;
+
+    // A gamepad that reports whatever it's told to, in order. Clones share
+    // their events, so one can be kept to drive the one given to a Gamepad.
+    #[derive(Debug, Default, Clone)]
+    struct MockGamepad {
+        events: Rc<RefCell<VecDeque<GamepadEvent>>>,
+    }
+
+    impl MockGamepad {
+        fn push(&self, event: GamepadEvent) {
+            self.events.borrow_mut().push_back(event);
+        }
+
+        fn press(&self, button: GamepadButton) {
+            self.push(GamepadEvent::Button(button, true));
+        }
+
+        fn release(&self, button: GamepadButton) {
+            self.push(GamepadEvent::Button(button, false));
+        }
+
+        fn set_axis(&self, axis: GamepadAxis, value: f32) {
+            self.push(GamepadEvent::Axis(axis, value));
+        }
+    }
+
+    impl GamepadDevice for MockGamepad {
+        fn poll(&mut self) -> Option<GamepadEvent> {
+            self.events.borrow_mut().pop_front()
+        }
+    }
+
+    fn gamepad() -> (MockGamepad, Gamepad) {
+        let mock = MockGamepad::default();
+        let mut gamepad = Gamepad::new(Box::new(mock.clone()));
+        gamepad.stick_dead_zone = 0.2;
+        gamepad.trigger_dead_zone = 0.1;
+        gamepad.curve = 2.0;
+        (mock, gamepad)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn buttons_pass_through_in_order() {
+        let (mock, mut gamepad) = gamepad();
+        mock.push(GamepadEvent::Connected);
+        mock.press(GamepadButton::South);
+        mock.release(GamepadButton::South);
+        mock.press(GamepadButton::North);
+        assert_eq!(gamepad.poll(), [
+            InputEvent::GamepadButton(GamepadButton::South, true),
+            InputEvent::GamepadButton(GamepadButton::South, false),
+            InputEvent::GamepadButton(GamepadButton::North, true),
+        ]);
+        assert!(gamepad.poll().is_empty());
+    }
+
+    #[test]
+    fn sticks_inside_the_dead_zone_do_nothing() {
+        let (mock, mut gamepad) = gamepad();
+        mock.set_axis(GamepadAxis::LeftStickX, 0.15);
+        mock.set_axis(GamepadAxis::LeftStickY, -0.1);
+        mock.set_axis(GamepadAxis::LeftTrigger, 0.1);
+        mock.set_axis(GamepadAxis::RightStickX, 0.19);
+        assert!(gamepad.poll().is_empty());
+        assert_eq!(gamepad.look(1.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn stick_range_is_stretched_past_the_dead_zone_then_curved() {
+        let (mock, mut gamepad) = gamepad();
+        // 0.6 is halfway from the dead zone to the edge, squared by the curve
+        mock.set_axis(GamepadAxis::LeftStickY, 0.6);
+        match gamepad.poll()[..] {
+            [InputEvent::GamepadAnalog(GamepadAnalog::LeftStickUp, amount)] => assert!(close(amount, 0.25)),
+            ref inputs => panic!("unexpected {:?}", inputs),
+        }
+
+        mock.set_axis(GamepadAxis::LeftStickY, -1.0);
+        let inputs = gamepad.poll();
+        assert_eq!(inputs, [
+            InputEvent::GamepadAnalog(GamepadAnalog::LeftStickUp, 0.0),
+            InputEvent::GamepadAnalog(GamepadAnalog::LeftStickDown, 1.0),
+        ]);
+    }
+
+    #[test]
+    fn the_dead_zone_is_round() {
+        let (mock, mut gamepad) = gamepad();
+        // each axis alone is inside the dead zone, together they're not
+        mock.set_axis(GamepadAxis::LeftStickX, 0.18);
+        mock.set_axis(GamepadAxis::LeftStickY, 0.18);
+        let inputs = gamepad.poll();
+        assert_eq!(inputs.len(), 2);
+        for input in inputs {
+            match input {
+                InputEvent::GamepadAnalog(GamepadAnalog::LeftStickUp | GamepadAnalog::LeftStickRight, amount) => {
+                    assert!(amount > 0.0 && amount < 0.01)
+                }
+                input => panic!("unexpected {:?}", input),
+            }
+        }
+    }
+
+    #[test]
+    fn triggers_go_from_zero_to_one() {
+        let (mock, mut gamepad) = gamepad();
+        mock.set_axis(GamepadAxis::LeftTrigger, 1.0);
+        mock.set_axis(GamepadAxis::RightTrigger, 0.55);
+        let inputs = gamepad.poll();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[0], InputEvent::GamepadAnalog(GamepadAnalog::LeftTrigger, 1.0));
+        match inputs[1] {
+            InputEvent::GamepadAnalog(GamepadAnalog::RightTrigger, amount) => assert!(close(amount, 0.25)),
+            input => panic!("unexpected {:?}", input),
+        }
+    }
+
+    #[test]
+    fn right_stick_looks_like_the_mouse() {
+        let (mock, mut gamepad) = gamepad();
+        gamepad.look_speed = 100.0;
+        mock.set_axis(GamepadAxis::RightStickX, 1.0);
+        mock.set_axis(GamepadAxis::RightStickY, 0.0);
+        // looking is asked for separately, nothing comes out of poll
+        assert!(gamepad.poll().is_empty());
+        let (dx, dy) = gamepad.look(0.5);
+        assert!(close(dx as f32, 50.0) && dy == 0.0);
+
+        // stick up is mouse up, which is negative
+        mock.set_axis(GamepadAxis::RightStickX, 0.0);
+        mock.set_axis(GamepadAxis::RightStickY, 1.0);
+        gamepad.poll();
+        let (dx, dy) = gamepad.look(0.5);
+        assert!(dx == 0.0 && close(dy as f32, -50.0));
+    }
+
+    #[test]
+    fn disconnecting_lets_go_of_everything() {
+        let (mock, mut gamepad) = gamepad();
+        mock.set_axis(GamepadAxis::LeftStickX, 1.0);
+        mock.set_axis(GamepadAxis::RightStickX, 1.0);
+        assert_eq!(gamepad.poll(), [InputEvent::GamepadAnalog(GamepadAnalog::LeftStickRight, 1.0)]);
+        mock.push(GamepadEvent::Disconnected);
+        assert_eq!(gamepad.poll(), [InputEvent::GamepadAnalog(GamepadAnalog::LeftStickRight, 0.0)]);
+        assert_eq!(gamepad.look(1.0), (0.0, 0.0));
+    }
+}
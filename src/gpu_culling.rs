@@ -0,0 +1,394 @@
+use std::mem;
+
+use wgpu::util::{DeviceExt, DrawIndexedIndirect};
+
+use crate::culling::{Aabb, Frustum};
+use crate::instance_buffer::InstanceBuffer;
+use crate::{model, InstanceRaw};
+
+const WORKGROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullParams {
+    planes: [[f32; 4]; 6],
+    instance_count: u32,
+    mesh_count: u32,
+    material_count: u32,
+    // uniforms require 16 byte spacing
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct MeshBounds {
+    center: [f32; 3],
+    // what its instances without a material of their own are drawn with
+    material: u32,
+    extents: [f32; 3],
+    _padding: u32,
+}
+
+impl CullParams {
+    fn new(frustum: &Frustum, instance_count: u32, mesh_count: u32, material_count: u32) -> Self {
+        let mut planes = [[0.0; 4]; 6];
+        for (plane, frustum_plane) in planes.iter_mut().zip(frustum.planes()) {
+            *plane = (*frustum_plane).into();
+        }
+        Self {
+            planes,
+            instance_count,
+            mesh_count,
+            material_count,
+            _padding: 0,
+        }
+    }
+}
+
+impl MeshBounds {
+    fn new(aabb: &Aabb, material: usize) -> Self {
+        Self {
+            center: aabb.center().into(),
+            material: material as u32,
+            extents: aabb.half_extents().into(),
+            _padding: 0,
+        }
+    }
+}
+
+// Frustum culling on the GPU. A compute pass tests every instance of every
+// mesh and appends the survivors to that mesh's slice of the culled
+// instance buffer, counting them straight into the mesh's indirect draw
+// arguments. The CPU never learns how many instances are visible. Each mesh
+// is drawn at full detail, as the arguments only cover its first LOD. The
+// culled copies have the material they're drawn with filled in, as the CPU
+// culled ones do.
+pub struct GpuCuller {
+    pipeline: wgpu::ComputePipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    params_buffer: wgpu::Buffer,
+    mesh_buffer: wgpu::Buffer,
+    culled_instance_buffer: wgpu::Buffer,
+    indirect_buffer: wgpu::Buffer,
+    // indexed by mesh, first_instance is always 0 as a non-zero one needs
+    // INDIRECT_FIRST_INSTANCE, each mesh gets its own vertex buffer slice
+    draws: Vec<DrawIndexedIndirect>,
+    instance_count: u32,
+    material_count: u32,
+    // of the instance buffer, each mesh gets room for this many culled
+    // instances
+    instance_capacity: usize,
+}
+
+impl GpuCuller {
+    // WebGL has neither compute shaders nor indirect draws
+    pub fn is_supported(adapter: &wgpu::Adapter) -> bool {
+        adapter.get_downlevel_capabilities().flags.contains(
+            wgpu::DownlevelFlags::COMPUTE_SHADERS | wgpu::DownlevelFlags::INDIRECT_EXECUTION
+        )
+    }
+
+    pub fn new(device: &wgpu::Device, model: &model::Model, instances: &InstanceBuffer) -> Self {
+        let storage = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    storage(1, true),
+                    storage(2, true),
+                    storage(3, false),
+                    storage(4, false),
+                ],
+                label: Some("gpu_culling_bind_group_layout"),
+            }
+        );
+
+        let pipeline = {
+            let layout = device.create_pipeline_layout(
+                &wgpu::PipelineLayoutDescriptor {
+                    label: Some("GPU Culling Pipeline Layout"),
+                    bind_group_layouts: &[&bind_group_layout],
+                    push_constant_ranges: &[],
+                }
+            );
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("GPU Culling Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("gpu_culling.wgsl").into()),
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("GPU Culling Pipeline"),
+                layout: Some(&layout),
+                module: &shader,
+                entry_point: "cs_main",
+            })
+        };
+
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("GPU Culling Params Buffer"),
+            size: mem::size_of::<CullParams>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mesh_bounds = model.meshes.iter()
+            .map(|mesh| MeshBounds::new(&mesh.bounds, mesh.material))
+            .collect::<Vec<_>>();
+        let mesh_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("GPU Culling Mesh Buffer"),
+                contents: bytemuck::cast_slice(&mesh_bounds),
+                usage: wgpu::BufferUsages::STORAGE,
+            }
+        );
+
+        let draws = model.meshes.iter()
+            .map(|mesh| DrawIndexedIndirect {
+                vertex_count: mesh.num_elements,
+                instance_count: 0,
+                base_index: 0,
+                vertex_offset: 0,
+                base_instance: 0,
+            })
+            .collect::<Vec<_>>();
+        let indirect_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("GPU Culling Indirect Buffer"),
+            size: (draws.len() * mem::size_of::<DrawIndexedIndirect>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::INDIRECT
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let culled_instance_buffer =
+            Self::create_culled_instance_buffer(device, instances.capacity(), draws.len());
+        let bind_group = Self::create_bind_group(
+            device,
+            &bind_group_layout,
+            &params_buffer,
+            &mesh_buffer,
+            instances.buffer(),
+            &culled_instance_buffer,
+            &indirect_buffer,
+        );
+
+        Self {
+            pipeline,
+            bind_group_layout,
+            bind_group,
+            params_buffer,
+            mesh_buffer,
+            culled_instance_buffer,
+            indirect_buffer,
+            draws,
+            instance_count: instances.len() as u32,
+            material_count: model.materials.len() as u32,
+            instance_capacity: instances.capacity(),
+        }
+    }
+
+    fn create_culled_instance_buffer(
+        device: &wgpu::Device,
+        instance_capacity: usize,
+        mesh_count: usize,
+    ) -> wgpu::Buffer {
+        let instance_size = mem::size_of::<InstanceRaw>();
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("GPU Culled Instance Buffer"),
+            size: (mesh_count * instance_capacity * instance_size) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::VERTEX,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        params_buffer: &wgpu::Buffer,
+        mesh_buffer: &wgpu::Buffer,
+        instance_buffer: &wgpu::Buffer,
+        culled_instance_buffer: &wgpu::Buffer,
+        indirect_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: mesh_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: instance_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: culled_instance_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: indirect_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("gpu_culling_bind_group"),
+        })
+    }
+
+    // must be called after the instance buffer is uploaded, in case
+    // instances came or went or the buffer was replaced
+    pub fn set_instances(&mut self, device: &wgpu::Device, instances: &InstanceBuffer) {
+        if instances.capacity() != self.instance_capacity {
+            self.culled_instance_buffer = Self::create_culled_instance_buffer(
+                device,
+                instances.capacity(),
+                self.draws.len(),
+            );
+            self.bind_group = Self::create_bind_group(
+                device,
+                &self.bind_group_layout,
+                &self.params_buffer,
+                &self.mesh_buffer,
+                instances.buffer(),
+                &self.culled_instance_buffer,
+                &self.indirect_buffer,
+            );
+            self.instance_capacity = instances.capacity();
+        }
+        self.instance_count = instances.len() as u32;
+    }
+
+    // records the culling pass, the indirect draws are valid once it has run
+    pub fn cull(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        frustum: &Frustum,
+    ) {
+        let params = CullParams::new(
+            frustum,
+            self.instance_count,
+            self.draws.len() as u32,
+            self.material_count,
+        );
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[params]));
+
+        // the shader counts up from zero every frame
+        let draws = self.draws.iter()
+            .flat_map(|draw| draw.as_bytes().iter().copied())
+            .collect::<Vec<_>>();
+        queue.write_buffer(&self.indirect_buffer, 0, &draws);
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("GPU Culling Pass"),
+        });
+        compute_pass.set_pipeline(&self.pipeline);
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        compute_pass.dispatch_workgroups(
+            self.instance_count.div_ceil(WORKGROUP_SIZE),
+            self.draws.len() as u32,
+            1,
+        );
+    }
+
+    // the slice of culled instances to bind for a mesh's indirect draw
+    pub fn instance_slice(&self, mesh: usize) -> wgpu::BufferSlice<'_> {
+        // every mesh's slice fits in the culled buffer, the instance buffer
+        // never holds less than one instance
+        let instance_count = self.instance_count.max(1) as usize;
+        let stride = (instance_count * mem::size_of::<InstanceRaw>()) as u64;
+        self.culled_instance_buffer.slice(mesh as u64 * stride..(mesh as u64 + 1) * stride)
+    }
+
+    pub fn indirect_buffer(&self) -> &wgpu::Buffer {
+        &self.indirect_buffer
+    }
+
+    pub fn indirect_offset(mesh: usize) -> wgpu::BufferAddress {
+        (mesh * mem::size_of::<DrawIndexedIndirect>()) as wgpu::BufferAddress
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, Point3};
+
+    use super::*;
+    use crate::camera::Projection;
+
+    // the number in gpu_culling.wgsl between `prefix` and `suffix`
+    fn shader_number(prefix: &str, suffix: char) -> usize {
+        let source = include_str!("gpu_culling.wgsl");
+        let start = source.find(prefix).expect(prefix) + prefix.len();
+        let end = start + source[start..].find(suffix).unwrap();
+        source[start..end].trim().parse().unwrap()
+    }
+
+    #[test]
+    fn the_shader_copies_whole_instances() {
+        assert_eq!(mem::size_of::<InstanceRaw>() % 4, 0);
+        assert_eq!(shader_number("array<u32, ", '>'), mem::size_of::<InstanceRaw>() / 4);
+        assert_eq!(
+            shader_number("let MATERIAL_WORD: u32 = ", 'u'),
+            mem::offset_of!(InstanceRaw, material) / 4,
+        );
+    }
+
+    #[test]
+    fn buffers_match_the_shader_layouts() {
+        // six vec4 planes then three counts, rounded up to 16 bytes
+        assert_eq!(mem::size_of::<CullParams>(), 112);
+        // the material fills out the centre's vec3
+        assert_eq!(mem::offset_of!(MeshBounds, material), 12);
+        assert_eq!(mem::offset_of!(MeshBounds, extents), 16);
+        assert_eq!(mem::size_of::<MeshBounds>(), 32);
+        // five words per draw
+        assert_eq!(GpuCuller::indirect_offset(3), 60);
+    }
+
+    #[test]
+    fn params_carry_the_frustum_planes() {
+        let projection = Projection::new(1, 1, Deg(90.0), 0.1, 100.0);
+        let frustum = Frustum::from_matrix(projection.calc_matrix());
+        let params = CullParams::new(&frustum, 7, 2, 3);
+        for (plane, frustum_plane) in params.planes.iter().zip(frustum.planes()) {
+            assert_eq!(*plane, Into::<[f32; 4]>::into(*frustum_plane));
+        }
+        assert_eq!((params.instance_count, params.mesh_count, params.material_count), (7, 2, 3));
+    }
+
+    #[test]
+    fn mesh_bounds_are_centre_and_half_extents() {
+        let bounds = MeshBounds::new(
+            &Aabb {
+                min: Point3::new(-1.0, 0.0, 2.0),
+                max: Point3::new(3.0, 2.0, 4.0),
+            },
+            5,
+        );
+        assert_eq!(bounds.center, [1.0, 1.0, 3.0]);
+        assert_eq!(bounds.extents, [2.0, 1.0, 1.0]);
+        assert_eq!(bounds.material, 5);
+    }
+}
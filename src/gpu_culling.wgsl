@@ -0,0 +1,93 @@
+// Compute shader
+
+struct CullParams {
+    // inward facing (normal, distance) frustum planes
+    planes: array<vec4<f32>, 6>,
+    instance_count: u32,
+    mesh_count: u32,
+    material_count: u32,
+}
+@group(0) @binding(0)
+var<uniform> params: CullParams;
+
+// model space bounds and the mesh's own material
+struct MeshBounds {
+    center: vec3<f32>,
+    material: u32,
+    extents: vec3<f32>,
+}
+@group(0) @binding(1)
+var<storage, read> meshes: array<MeshBounds>;
+
+// InstanceRaw is a mat4 followed by a tightly packed mat3, a tint and a
+// material index, which doesn't match WGSL's layout, so it is passed around
+// as plain words. Copying them as floats could change the index.
+struct InstanceRaw {
+    data: array<u32, 29>,
+}
+// where the material index sits in the words
+let MATERIAL_WORD: u32 = 28u;
+@group(0) @binding(2)
+var<storage, read> instances: array<InstanceRaw>;
+@group(0) @binding(3)
+var<storage, read_write> culled: array<InstanceRaw>;
+
+// matches wgpu's DrawIndexedIndirect layout
+struct DrawIndexedIndirect {
+    index_count: u32,
+    instance_count: atomic<u32>,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+@group(0) @binding(4)
+var<storage, read_write> draws: array<DrawIndexedIndirect>;
+
+fn column(instance: u32, i: u32) -> vec3<f32> {
+    let base = i * 4u;
+    return bitcast<vec3<f32>>(vec3<u32>(
+        instances[instance].data[base],
+        instances[instance].data[base + 1u],
+        instances[instance].data[base + 2u],
+    ));
+}
+
+// x is the instance, y the mesh
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let instance = id.x;
+    let mesh = id.y;
+    if (instance >= params.instance_count || mesh >= params.mesh_count) {
+        return;
+    }
+
+    // transform the mesh bounds into world space
+    let x = column(instance, 0u);
+    let y = column(instance, 1u);
+    let z = column(instance, 2u);
+    let w = column(instance, 3u);
+    let local_center = meshes[mesh].center;
+    let local_extents = meshes[mesh].extents;
+    let center = x * local_center.x + y * local_center.y + z * local_center.z + w;
+    let extents = abs(x) * local_extents.x
+        + abs(y) * local_extents.y
+        + abs(z) * local_extents.z;
+
+    for (var i = 0; i < 6; i += 1) {
+        let plane = params.planes[i];
+        // distance of the box's most positive corner along the normal
+        let radius = dot(abs(plane.xyz), extents);
+        if (dot(plane.xyz, center) + plane.w + radius < 0.0) {
+            return;
+        }
+    }
+
+    // filled in with the material it's drawn with, falling back to the
+    // mesh's like the CPU culling does
+    var raw = instances[instance];
+    if (raw.data[MATERIAL_WORD] >= params.material_count) {
+        raw.data[MATERIAL_WORD] = meshes[mesh].material;
+    }
+    let slot = atomicAdd(&draws[mesh].instance_count, 1u);
+    culled[mesh * params.instance_count + slot] = raw;
+}
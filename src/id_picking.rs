@@ -0,0 +1,427 @@
+use std::mem;
+use std::num::NonZeroU64;
+use std::sync::mpsc;
+
+use winit::dpi::PhysicalPosition;
+
+use crate::model::{self, DrawModel, Vertex};
+use crate::instance_buffer::InstanceBuffer;
+use crate::{texture, InstanceRaw, PipelineOptions};
+
+const ID_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Uint;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct PickParams {
+    mesh: u32,
+    mesh_count: u32,
+    // uniforms require 16 byte spacing
+    _padding: [u32; 2],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PickedId {
+    pub instance: usize,
+    pub mesh: usize,
+}
+
+impl PickedId {
+    // None for the background, which is cleared to 0
+    fn from_id(id: u32, mesh_count: u32) -> Option<Self> {
+        id.checked_sub(1).map(|id| Self {
+            instance: (id / mesh_count) as usize,
+            mesh: (id % mesh_count) as usize,
+        })
+    }
+}
+
+enum Readback {
+    Idle,
+    // waiting for the next frame to render the IDs
+    Requested(PhysicalPosition<u32>),
+    // the copy is recorded, the buffer can be mapped once it's submitted
+    Copied,
+    Mapping(mpsc::Receiver<Result<(), wgpu::BufferAsyncError>>),
+}
+
+impl Readback {
+    // false while an earlier pick is still being read back
+    fn request(&mut self, position: PhysicalPosition<u32>) -> bool {
+        match self {
+            Readback::Idle | Readback::Requested(_) => {
+                *self = Readback::Requested(position);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // the requested pixel may no longer exist
+    fn resize(&mut self) {
+        if let Readback::Requested(_) = self {
+            *self = Readback::Idle;
+        }
+    }
+}
+
+// each mesh's params, `stride` bytes apart
+fn params_data(mesh_count: u32, stride: u32) -> Vec<u8> {
+    let params_size = mem::size_of::<PickParams>();
+    let mut data = vec![0u8; (stride * mesh_count.max(1)) as usize];
+    for mesh in 0..mesh_count {
+        let params = PickParams {
+            mesh,
+            mesh_count,
+            _padding: [0; 2],
+        };
+        let offset = (mesh * stride) as usize;
+        data[offset..offset + params_size].copy_from_slice(bytemuck::bytes_of(&params));
+    }
+    data
+}
+
+// Picks by rendering `instance * mesh_count + mesh + 1` for every pixel into
+// an R32Uint target and reading back the one under the cursor. Only that
+// pixel is rasterised and the readback is mapped asynchronously, so a pick
+// lands a frame or two after it was asked for. Every instance is drawn from
+// the whole instance buffer, in order, so the instance index is the
+// instance ID.
+pub struct IdPicker {
+    pipeline: wgpu::RenderPipeline,
+    params_bind_group: wgpu::BindGroup,
+    // distance between each mesh's params in params_buffer
+    params_stride: u32,
+    mesh_count: u32,
+    id_texture: wgpu::Texture,
+    id_view: wgpu::TextureView,
+    width: u32,
+    height: u32,
+    depth_texture: texture::Texture,
+    readback_buffer: wgpu::Buffer,
+    readback: Readback,
+}
+
+impl IdPicker {
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        bind_group_layouts: [&wgpu::BindGroupLayout; 3],
+        model: &model::Model,
+    ) -> Self {
+        let params_size = mem::size_of::<PickParams>() as u32;
+        let params_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: NonZeroU64::new(params_size as u64),
+                    },
+                    count: None,
+                }],
+                label: Some("id_picking_bind_group_layout"),
+            }
+        );
+
+        // one set of params per mesh, picked with a dynamic offset
+        let mesh_count = model.meshes.len() as u32;
+        let alignment = device.limits().min_uniform_buffer_offset_alignment;
+        let params_stride = params_size.div_ceil(alignment) * alignment;
+        let params_buffer = wgpu::util::DeviceExt::create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("ID Picking Params Buffer"),
+                contents: &params_data(mesh_count, params_stride),
+                usage: wgpu::BufferUsages::UNIFORM,
+            }
+        );
+        let params_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &params_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &params_buffer,
+                    offset: 0,
+                    size: NonZeroU64::new(params_size as u64),
+                }),
+            }],
+            label: Some("id_picking_bind_group"),
+        });
+
+        // laid out like the main pipeline so DrawModel's bind groups still
+        // line up, the params go after them
+        let [texture_layout, camera_layout, light_layout] = bind_group_layouts;
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("ID Picking Pipeline Layout"),
+            bind_group_layouts: &[texture_layout, camera_layout, light_layout, &params_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = wgpu::ShaderModuleDescriptor {
+            label: Some("ID Picking Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("id_picking.wgsl").into()),
+        };
+        let pipeline = crate::create_render_pipeline_with_options(
+            device,
+            shader,
+            &layout,
+            &[model::ModelVertex::desc(), InstanceRaw::desc()],
+            ID_FORMAT,
+            Some(texture::Texture::DEPTH_FORMAT),
+            PipelineOptions {
+                blend: None,
+                ..Default::default()
+            },
+        );
+
+        let (id_texture, id_view) = Self::create_id_texture(device, config);
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("ID Picking Readback Buffer"),
+            size: mem::size_of::<u32>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            pipeline,
+            params_bind_group,
+            params_stride,
+            mesh_count,
+            id_texture,
+            id_view,
+            width: config.width,
+            height: config.height,
+            depth_texture: texture::Texture::create_depth_texture(
+                device,
+                config,
+                "id_picking_depth_texture",
+            ),
+            readback_buffer,
+            readback: Readback::Idle,
+        }
+    }
+
+    fn create_id_texture(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> (wgpu::Texture, wgpu::TextureView) {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("ID Picking Texture"),
+            size: wgpu::Extent3d {
+                width: config.width,
+                height: config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: ID_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        (texture, view)
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        (self.id_texture, self.id_view) = Self::create_id_texture(device, config);
+        self.width = config.width;
+        self.height = config.height;
+        self.depth_texture = texture::Texture::create_depth_texture(
+            device,
+            config,
+            "id_picking_depth_texture",
+        );
+        self.readback.resize();
+    }
+
+    // false while an earlier pick is still being read back
+    pub fn request(&mut self, position: PhysicalPosition<u32>) -> bool {
+        self.readback.request(position)
+    }
+
+    // draws the IDs under a requested pixel and copies it out
+    pub fn render(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        model: &model::Model,
+        instances: &InstanceBuffer,
+        camera_bind_group: &wgpu::BindGroup,
+        light_bind_group: &wgpu::BindGroup,
+    ) {
+        let Readback::Requested(position) = self.readback else {
+            return;
+        };
+        if position.x >= self.width || position.y >= self.height {
+            self.readback = Readback::Idle;
+            return;
+        }
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("ID Picking Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.id_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(texture::Texture::DEPTH_CLEAR),
+                        store: false,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_scissor_rect(position.x, position.y, 1, 1);
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_vertex_buffer(1, instances.buffer().slice(..));
+            // cutouts and blending are ignored, the whole triangle counts
+            for (i, mesh) in model.meshes.iter().enumerate() {
+                render_pass.set_bind_group(
+                    3,
+                    &self.params_bind_group,
+                    &[i as u32 * self.params_stride],
+                );
+                render_pass.draw_mesh_instanced(
+                    mesh,
+                    &model.materials[mesh.material],
+                    0..instances.len() as u32,
+                    camera_bind_group,
+                    light_bind_group,
+                );
+            }
+        }
+
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &self.id_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: position.x,
+                    y: position.y,
+                    z: 0,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &self.readback_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: None,
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.readback = Readback::Copied;
+    }
+
+    // call once the encoder passed to render has been submitted
+    pub fn map_readback(&mut self) {
+        if let Readback::Copied = self.readback {
+            let (sender, receiver) = mpsc::channel();
+            self.readback_buffer
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |result| {
+                    let _ = sender.send(result);
+                });
+            self.readback = Readback::Mapping(receiver);
+        }
+    }
+
+    // Some once a readback finishes, holding what was under the pixel
+    pub fn poll(&mut self, device: &wgpu::Device) -> Option<Option<PickedId>> {
+        let Readback::Mapping(receiver) = &self.readback else {
+            return None;
+        };
+        device.poll(wgpu::Maintain::Poll);
+        let result = receiver.try_recv().ok()?;
+        self.readback = Readback::Idle;
+        if result.is_err() {
+            return Some(None);
+        }
+
+        let id = {
+            let data = self.readback_buffer.slice(..).get_mapped_range();
+            u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+        };
+        self.readback_buffer.unmap();
+        Some(PickedId::from_id(id, self.mesh_count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // as id_picking.wgsl writes them
+    fn id(instance: u32, mesh: u32, mesh_count: u32) -> u32 {
+        instance * mesh_count + mesh + 1
+    }
+
+    #[test]
+    fn ids_decode_to_what_was_drawn() {
+        assert_eq!(PickedId::from_id(0, 3), None);
+        for instance in 0..4 {
+            for mesh in 0..3 {
+                assert_eq!(
+                    PickedId::from_id(id(instance, mesh, 3), 3),
+                    Some(PickedId {
+                        instance: instance as usize,
+                        mesh: mesh as usize,
+                    }),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn params_sit_a_stride_apart() {
+        let data = params_data(3, 256);
+        assert_eq!(data.len(), 768);
+        for mesh in 0..3 {
+            let offset = mesh as usize * 256;
+            let params: PickParams =
+                bytemuck::pod_read_unaligned(&data[offset..offset + mem::size_of::<PickParams>()]);
+            assert_eq!((params.mesh, params.mesh_count), (mesh, 3));
+        }
+        // still something to bind without any meshes
+        assert_eq!(params_data(0, 256).len(), 256);
+    }
+
+    #[test]
+    fn requests_replace_each_other_until_copied() {
+        let mut readback = Readback::Idle;
+        assert!(readback.request(PhysicalPosition::new(1, 2)));
+        assert!(readback.request(PhysicalPosition::new(3, 4)));
+        assert!(matches!(readback, Readback::Requested(p) if p == PhysicalPosition::new(3, 4)));
+
+        readback = Readback::Copied;
+        assert!(!readback.request(PhysicalPosition::new(5, 6)));
+        let (_sender, receiver) = mpsc::channel();
+        readback = Readback::Mapping(receiver);
+        assert!(!readback.request(PhysicalPosition::new(5, 6)));
+        assert!(matches!(readback, Readback::Mapping(_)));
+    }
+
+    #[test]
+    fn resizing_only_cancels_pending_requests() {
+        let mut readback = Readback::Requested(PhysicalPosition::new(1, 2));
+        readback.resize();
+        assert!(matches!(readback, Readback::Idle));
+
+        // a copy already made still reads back
+        readback = Readback::Copied;
+        readback.resize();
+        assert!(matches!(readback, Readback::Copied));
+    }
+}
@@ -0,0 +1,508 @@
+use std::io;
+use std::path::Path;
+
+use winit::dpi::PhysicalPosition;
+use winit::event::{
+    ElementState, KeyboardInput, ModifiersState, MouseButton, MouseScrollDelta, VirtualKeyCode,
+    WindowEvent,
+};
+
+// Everything the player can do, bound to keys, mouse buttons or the scroll
+// wheel by an InputMap. Held actions see a press and a release, scrolling
+// drives `zoom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    Jump,
+    Crouch,
+    Sprint,
+    Slow,
+    RollLeft,
+    RollRight,
+    Look,
+    ToggleMouseLook,
+    Pan,
+    Zoom,
+    Pick,
+    SwitchCamera,
+    ToggleProjection,
+    ShiftLens,
+    ToggleOblique,
+    RecordPath,
+    PlayPath,
+    ToggleGpuPicking,
+    ToggleGpuCulling,
+    ToggleDebugDraw,
+    ToggleDebugMaterial,
+    ToggleMaterialArray,
+    RenderShaded,
+    RenderWireframe,
+    RenderNormals,
+    RenderTangents,
+    RenderBitangents,
+    RenderUvChecker,
+    RenderDepth,
+    SpawnInstance,
+    RemoveSelection,
+    RaiseSelection,
+    LowerSelection,
+    SaveScene,
+    Quit,
+}
+
+impl Action {
+    const ALL: [Self; 39] = [
+        Self::MoveForward,
+        Self::MoveBackward,
+        Self::MoveLeft,
+        Self::MoveRight,
+        Self::Jump,
+        Self::Crouch,
+        Self::Sprint,
+        Self::Slow,
+        Self::RollLeft,
+        Self::RollRight,
+        Self::Look,
+        Self::ToggleMouseLook,
+        Self::Pan,
+        Self::Zoom,
+        Self::Pick,
+        Self::SwitchCamera,
+        Self::ToggleProjection,
+        Self::ShiftLens,
+        Self::ToggleOblique,
+        Self::RecordPath,
+        Self::PlayPath,
+        Self::ToggleGpuPicking,
+        Self::ToggleGpuCulling,
+        Self::ToggleDebugDraw,
+        Self::ToggleDebugMaterial,
+        Self::ToggleMaterialArray,
+        Self::RenderShaded,
+        Self::RenderWireframe,
+        Self::RenderNormals,
+        Self::RenderTangents,
+        Self::RenderBitangents,
+        Self::RenderUvChecker,
+        Self::RenderDepth,
+        Self::SpawnInstance,
+        Self::RemoveSelection,
+        Self::RaiseSelection,
+        Self::LowerSelection,
+        Self::SaveScene,
+        Self::Quit,
+    ];
+
+    // as written in input config files
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::MoveForward => "move_forward",
+            Self::MoveBackward => "move_backward",
+            Self::MoveLeft => "move_left",
+            Self::MoveRight => "move_right",
+            Self::Jump => "jump",
+            Self::Crouch => "crouch",
+            Self::Sprint => "sprint",
+            Self::Slow => "slow",
+            Self::RollLeft => "roll_left",
+            Self::RollRight => "roll_right",
+            Self::Look => "look",
+            Self::ToggleMouseLook => "toggle_mouse_look",
+            Self::Pan => "pan",
+            Self::Zoom => "zoom",
+            Self::Pick => "pick",
+            Self::SwitchCamera => "switch_camera",
+            Self::ToggleProjection => "toggle_projection",
+            Self::ShiftLens => "shift_lens",
+            Self::ToggleOblique => "toggle_oblique",
+            Self::RecordPath => "record_path",
+            Self::PlayPath => "play_path",
+            Self::ToggleGpuPicking => "toggle_gpu_picking",
+            Self::ToggleGpuCulling => "toggle_gpu_culling",
+            Self::ToggleDebugDraw => "toggle_debug_draw",
+            Self::ToggleDebugMaterial => "toggle_debug_material",
+            Self::ToggleMaterialArray => "toggle_material_array",
+            Self::RenderShaded => "render_shaded",
+            Self::RenderWireframe => "render_wireframe",
+            Self::RenderNormals => "render_normals",
+            Self::RenderTangents => "render_tangents",
+            Self::RenderBitangents => "render_bitangents",
+            Self::RenderUvChecker => "render_uv_checker",
+            Self::RenderDepth => "render_depth",
+            Self::SpawnInstance => "spawn_instance",
+            Self::RemoveSelection => "remove_selection",
+            Self::RaiseSelection => "raise_selection",
+            Self::LowerSelection => "lower_selection",
+            Self::SaveScene => "save_scene",
+            Self::Quit => "quit",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|action| action.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GamepadButton {
+    South,
+    East,
+    West,
+    North,
+    LeftBumper,
+    RightBumper,
+    Select,
+    Start,
+    LeftStick,
+    RightStick,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+impl GamepadButton {
+    pub const ALL: [Self; 14] = [
+        Self::South,
+        Self::East,
+        Self::West,
+        Self::North,
+        Self::LeftBumper,
+        Self::RightBumper,
+        Self::Select,
+        Self::Start,
+        Self::LeftStick,
+        Self::RightStick,
+        Self::DPadUp,
+        Self::DPadDown,
+        Self::DPadLeft,
+        Self::DPadRight,
+    ];
+
+    // by its name here, without the Pad prefix config files give it
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|button| format!("{:?}", button) == name)
+    }
+}
+
+// A stick pushed one way or a trigger, from 0 to 1. The right stick looks
+// around like the mouse does, so only the left one can be bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GamepadAnalog {
+    LeftStickUp,
+    LeftStickDown,
+    LeftStickLeft,
+    LeftStickRight,
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl GamepadAnalog {
+    pub const ALL: [Self; 6] = [
+        Self::LeftStickUp,
+        Self::LeftStickDown,
+        Self::LeftStickLeft,
+        Self::LeftStickRight,
+        Self::LeftTrigger,
+        Self::RightTrigger,
+    ];
+
+    // by its name here, without the Pad prefix config files give it
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|analog| format!("{:?}", analog) == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+    Scroll,
+    Gamepad(GamepadButton),
+    // drives the actions it's bound to by how far it's pushed
+    GamepadAnalog(GamepadAnalog),
+}
+
+// the keys that can be named in a config file, by their winit names
+const KEYS: &[VirtualKeyCode] = {
+    use VirtualKeyCode::*;
+    &[
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+        Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9,
+        F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+        Up, Down, Left, Right,
+        Space, Tab, Return, Escape, Back, Delete, Insert, Home, End, PageUp, PageDown,
+        LShift, RShift, LControl, RControl, LAlt, RAlt,
+        Numpad0, Numpad1, Numpad2, Numpad3, Numpad4,
+        Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    ]
+};
+
+pub fn parse_key(name: &str) -> Option<VirtualKeyCode> {
+    KEYS.iter()
+        .copied()
+        .find(|key| format!("{:?}", key) == name)
+}
+
+impl Binding {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "Scroll" => Some(Self::Scroll),
+            "MouseLeft" => Some(Self::Mouse(MouseButton::Left)),
+            "MouseRight" => Some(Self::Mouse(MouseButton::Right)),
+            "MouseMiddle" => Some(Self::Mouse(MouseButton::Middle)),
+            _ => match name.strip_prefix("Pad") {
+                Some(input) => GamepadButton::parse(input)
+                    .map(Self::Gamepad)
+                    .or_else(|| GamepadAnalog::parse(input).map(Self::GamepadAnalog)),
+                None => parse_key(name).map(Self::Key),
+            },
+        }
+    }
+}
+
+const DEFAULT_BINDINGS: &str = "
+move_forward = W, Up, PadLeftStickUp
+move_backward = S, Down, PadLeftStickDown
+move_left = A, Left, PadLeftStickLeft
+move_right = D, Right, PadLeftStickRight
+jump = Space, PadRightTrigger
+crouch = LShift, PadLeftTrigger
+sprint = LControl, PadLeftStick
+slow = LAlt, PadRightStick
+roll_left = Q, PadLeftBumper
+roll_right = E, PadRightBumper
+look = MouseLeft
+toggle_mouse_look = M
+pan = MouseMiddle
+zoom = Scroll
+pick = MouseRight
+switch_camera = Tab, PadSelect
+toggle_projection = F11, PadNorth
+shift_lens = H
+toggle_oblique = O
+record_path = R
+play_path = P
+toggle_gpu_picking = G
+toggle_gpu_culling = F10
+toggle_debug_draw = F8
+toggle_debug_material = F9
+toggle_material_array = B
+render_shaded = F1
+render_wireframe = F2
+render_normals = F3
+render_tangents = F4
+render_bitangents = F5
+render_uv_checker = F6
+render_depth = F7
+spawn_instance = Insert
+remove_selection = Delete
+raise_selection = PageUp
+lower_selection = PageDown
+save_scene = F12
+quit = Escape
+";
+
+// Which actions each binding triggers. Config files have a line per action,
+// `action = binding, binding`, with `#` starting a comment. Bindings are
+// winit key names, MouseLeft, MouseRight, MouseMiddle, Scroll or a gamepad
+// button prefixed with Pad, like PadSouth or PadLeftBumper. The left stick's
+// directions and the triggers, like PadLeftStickUp or PadRightTrigger, only
+// reach the actions that move the camera.
+#[derive(Debug, Clone)]
+pub struct InputMap {
+    bindings: Vec<(Binding, Action)>,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        Self {
+            bindings: Vec::new(),
+        }
+        .merge(DEFAULT_BINDINGS)
+        .expect("default bindings are valid")
+    }
+}
+
+impl InputMap {
+    // the defaults, with the actions in the file rebound
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::default().merge(&text)
+    }
+
+    // actions listed in `text` lose their old bindings
+    fn merge(mut self, text: &str) -> io::Result<Self> {
+        for (number, line) in text.lines().enumerate() {
+            let invalid = |message: &str| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} on input config line {}", message, number + 1),
+            );
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, bindings) = line.split_once('=')
+                .ok_or_else(|| invalid("expected `action = bindings`"))?;
+            let action = Action::from_name(name.trim())
+                .ok_or_else(|| invalid("unknown action"))?;
+            self.bindings.retain(|&(_, bound)| bound != action);
+            for binding in bindings.split(',').map(str::trim).filter(|b| !b.is_empty()) {
+                let binding = Binding::parse(binding)
+                    .ok_or_else(|| invalid("unknown binding"))?;
+                self.bindings.push((binding, action));
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn actions(&self, binding: Binding) -> impl Iterator<Item = Action> + '_ {
+        self.bindings.iter()
+            .filter(move |&&(bound, _)| bound == binding)
+            .map(|&(_, action)| action)
+    }
+}
+
+// The window, device and gamepad events State::input reacts to, with the
+// parts it doesn't need stripped off. Keys that can't be bound are dropped, nothing
+// can happen when they're pressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    CursorMoved(PhysicalPosition<f64>),
+    Key(VirtualKeyCode, bool),
+    Modifiers(ModifiersState),
+    Scroll(MouseScrollDelta),
+    MouseButton(MouseButton, bool),
+    // raw device movement, not tied to the cursor
+    MouseMotion(f64, f64),
+    Focused(bool),
+    GamepadButton(GamepadButton, bool),
+    GamepadAnalog(GamepadAnalog, f32),
+    // the right stick as mouse movement, looking around without Look held
+    GamepadLook(f64, f64),
+}
+
+impl InputEvent {
+    pub fn from_window_event(event: &WindowEvent) -> Option<Self> {
+        let pressed = |state: &ElementState| *state == ElementState::Pressed;
+        match event {
+            WindowEvent::CursorMoved { position, .. } => Some(Self::CursorMoved(*position)),
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput {
+                    virtual_keycode: Some(key),
+                    state,
+                    ..
+                },
+                ..
+            } if KEYS.contains(key) => Some(Self::Key(*key, pressed(state))),
+            WindowEvent::ModifiersChanged(modifiers) => Some(Self::Modifiers(*modifiers)),
+            WindowEvent::MouseWheel { delta, .. } => Some(Self::Scroll(*delta)),
+            WindowEvent::MouseInput { state, button, .. } => {
+                Some(Self::MouseButton(*button, pressed(state)))
+            }
+            WindowEvent::Focused(focused) => Some(Self::Focused(*focused)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actions(map: &InputMap, binding: Binding) -> Vec<Action> {
+        map.actions(binding).collect()
+    }
+
+    #[test]
+    fn every_action_has_a_unique_name() {
+        for action in Action::ALL {
+            assert_eq!(Action::from_name(action.name()), Some(action));
+        }
+    }
+
+    #[test]
+    fn defaults_bind_keys_mouse_and_pads() {
+        let map = InputMap::default();
+        assert_eq!(actions(&map, Binding::Key(VirtualKeyCode::W)), [Action::MoveForward]);
+        assert_eq!(actions(&map, Binding::Key(VirtualKeyCode::Up)), [Action::MoveForward]);
+        assert_eq!(actions(&map, Binding::Mouse(MouseButton::Right)), [Action::Pick]);
+        assert_eq!(actions(&map, Binding::Scroll), [Action::Zoom]);
+        assert_eq!(
+            actions(&map, Binding::Gamepad(GamepadButton::North)),
+            [Action::ToggleProjection],
+        );
+        assert_eq!(actions(&map, Binding::Key(VirtualKeyCode::F7)), [Action::RenderDepth]);
+        assert_eq!(
+            actions(&map, Binding::GamepadAnalog(GamepadAnalog::LeftStickUp)),
+            [Action::MoveForward],
+        );
+        assert_eq!(
+            actions(&map, Binding::GamepadAnalog(GamepadAnalog::LeftTrigger)),
+            [Action::Crouch],
+        );
+    }
+
+    #[test]
+    fn sticks_and_triggers_can_be_rebound() {
+        let map = InputMap::default()
+            .merge("roll_left = PadLeftTrigger\ncrouch = C\nmove_forward = PadLeftStickDown")
+            .unwrap();
+        assert_eq!(
+            actions(&map, Binding::GamepadAnalog(GamepadAnalog::LeftTrigger)),
+            [Action::RollLeft],
+        );
+        assert_eq!(
+            actions(&map, Binding::GamepadAnalog(GamepadAnalog::LeftStickDown)),
+            [Action::MoveBackward, Action::MoveForward],
+        );
+        assert!(actions(&map, Binding::GamepadAnalog(GamepadAnalog::LeftStickUp)).is_empty());
+        // the stick itself is still a button when clicked
+        assert_eq!(
+            actions(&map, Binding::Gamepad(GamepadButton::LeftStick)),
+            [Action::Sprint],
+        );
+    }
+
+    #[test]
+    fn config_rebinds_only_the_actions_it_lists() {
+        let map = InputMap::default()
+            .merge("# comment\n\n move_forward = I, PadSouth # trailing\nquit =\n")
+            .unwrap();
+        assert!(actions(&map, Binding::Key(VirtualKeyCode::W)).is_empty());
+        assert_eq!(actions(&map, Binding::Key(VirtualKeyCode::I)), [Action::MoveForward]);
+        assert_eq!(
+            actions(&map, Binding::Gamepad(GamepadButton::South)),
+            [Action::MoveForward],
+        );
+        assert!(actions(&map, Binding::Key(VirtualKeyCode::Escape)).is_empty());
+        assert_eq!(actions(&map, Binding::Key(VirtualKeyCode::S)), [Action::MoveBackward]);
+    }
+
+    #[test]
+    fn bindings_can_trigger_several_actions() {
+        let map = InputMap::default().merge("jump = W").unwrap();
+        assert_eq!(
+            actions(&map, Binding::Key(VirtualKeyCode::W)),
+            [Action::MoveForward, Action::Jump],
+        );
+    }
+
+    #[test]
+    fn bad_config_lines_are_errors() {
+        for (text, message) in [
+            ("move_forward W", "expected `action = bindings` on input config line 1"),
+            ("\nfly = W", "unknown action on input config line 2"),
+            ("jump = Space, Hyperspace", "unknown binding on input config line 1"),
+            ("jump = PadTurbo", "unknown binding on input config line 1"),
+        ] {
+            let error = InputMap::default().merge(text).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), message);
+        }
+    }
+}
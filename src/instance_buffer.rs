@@ -0,0 +1,227 @@
+use std::collections::BTreeSet;
+use std::mem;
+use std::ops::Range;
+
+use crate::{Instance, InstanceRaw};
+
+// The copy of the instances kept on the CPU along with which of them have
+// changed since they were last written out.
+struct InstanceData {
+    instances: Vec<InstanceRaw>,
+    // indices changed since the last upload
+    dirty: BTreeSet<usize>,
+}
+
+impl InstanceData {
+    fn new(instances: &[Instance]) -> Self {
+        Self {
+            instances: instances.iter().map(Instance::to_raw).collect(),
+            dirty: (0..instances.len()).collect(),
+        }
+    }
+
+    fn push(&mut self, instance: &Instance) {
+        self.dirty.insert(self.instances.len());
+        self.instances.push(instance.to_raw());
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.instances.remove(index);
+        self.dirty.extend(index..self.instances.len());
+    }
+
+    fn set(&mut self, index: usize, instance: &Instance) {
+        self.instances[index] = instance.to_raw();
+        self.dirty.insert(index);
+    }
+
+    // the changed instances as runs of neighbours, clearing them
+    fn take_dirty_ranges(&mut self) -> Vec<Range<usize>> {
+        // removals can leave indices past the end
+        let mut dirty = mem::take(&mut self.dirty)
+            .into_iter()
+            .take_while(|&index| index < self.instances.len())
+            .peekable();
+        let mut ranges = Vec::new();
+        while let Some(start) = dirty.next() {
+            let mut end = start + 1;
+            while dirty.next_if_eq(&end).is_some() {
+                end += 1;
+            }
+            ranges.push(start..end);
+        }
+        ranges
+    }
+}
+
+// Instance data on the GPU that changes an instance at a time. Changes are
+// made to a copy kept here and only the instances they touched are written
+// on upload, a run of neighbours in one go. Outgrowing the buffer replaces
+// it with one twice the size, which is then written in full.
+pub struct InstanceBuffer {
+    buffer: wgpu::Buffer,
+    usage: wgpu::BufferUsages,
+    // in instances
+    capacity: usize,
+    data: InstanceData,
+}
+
+impl InstanceBuffer {
+    pub fn new(device: &wgpu::Device, usage: wgpu::BufferUsages, instances: &[Instance]) -> Self {
+        let usage = usage | wgpu::BufferUsages::COPY_DST;
+        // zero sized buffers can't be bound
+        let capacity = instances.len().max(1);
+        Self {
+            buffer: Self::create_buffer(device, usage, capacity),
+            usage,
+            capacity,
+            data: InstanceData::new(instances),
+        }
+    }
+
+    fn create_buffer(
+        device: &wgpu::Device,
+        usage: wgpu::BufferUsages,
+        capacity: usize,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Instance Buffer"),
+            size: (capacity * mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.instances.len()
+    }
+
+    // changes when the buffer is replaced, so anything bound to it needs
+    // rebinding
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    // what the buffer holds, or will once uploaded
+    pub fn instances(&self) -> &[InstanceRaw] {
+        &self.data.instances
+    }
+
+    pub fn push(&mut self, instance: &Instance) {
+        self.data.push(instance);
+    }
+
+    // the instances after it move down a place
+    pub fn remove(&mut self, index: usize) {
+        self.data.remove(index);
+    }
+
+    pub fn set(&mut self, index: usize, instance: &Instance) {
+        self.data.set(index, instance);
+    }
+
+    // must be called before the buffer is next used
+    pub fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let instances = &self.data.instances;
+        if instances.len() > self.capacity {
+            self.capacity = instances.len().max(self.capacity * 2);
+            self.buffer = Self::create_buffer(device, self.usage, self.capacity);
+            queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(instances));
+            self.data.dirty.clear();
+            return;
+        }
+
+        for range in self.data.take_dirty_ranges() {
+            let offset = (range.start * mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress;
+            queue.write_buffer(
+                &self.buffer,
+                offset,
+                bytemuck::cast_slice(&self.data.instances[range]),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+
+    use super::*;
+
+    fn at(x: f32) -> Instance {
+        Instance {
+            position: Vector3::new(x, 0.0, 0.0),
+            ..Default::default()
+        }
+    }
+
+    fn x(raw: &InstanceRaw) -> f32 {
+        raw.model[3][0]
+    }
+
+    fn dirty(data: &mut InstanceData) -> Vec<(usize, usize)> {
+        data.take_dirty_ranges().into_iter().map(|range| (range.start, range.end)).collect()
+    }
+
+    fn data(count: usize) -> InstanceData {
+        let instances = (0..count).map(|i| at(i as f32)).collect::<Vec<_>>();
+        let mut data = InstanceData::new(&instances);
+        data.take_dirty_ranges();
+        data
+    }
+
+    #[test]
+    fn new_instances_are_all_dirty() {
+        let mut data = InstanceData::new(&[at(0.0), at(1.0), at(2.0)]);
+        assert_eq!(dirty(&mut data), [(0, 3)]);
+        assert!(dirty(&mut data).is_empty());
+    }
+
+    #[test]
+    fn neighbouring_changes_coalesce() {
+        let mut data = data(10);
+        for index in [7, 2, 3, 9, 4, 8] {
+            data.set(index, &at(-1.0));
+        }
+        assert_eq!(dirty(&mut data), [(2, 5), (7, 10)]);
+        // setting the same instance twice writes it once
+        data.set(5, &at(0.0));
+        data.set(5, &at(1.0));
+        assert_eq!(dirty(&mut data), [(5, 6)]);
+        assert_eq!(x(&data.instances[5]), 1.0);
+    }
+
+    #[test]
+    fn pushes_are_dirty_at_the_end() {
+        let mut data = data(3);
+        data.push(&at(3.0));
+        data.push(&at(4.0));
+        assert_eq!(dirty(&mut data), [(3, 5)]);
+        assert_eq!(data.instances.iter().map(x).collect::<Vec<_>>(), [0.0, 1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn removing_moves_later_instances_down_and_marks_them() {
+        let mut data = data(5);
+        data.remove(1);
+        assert_eq!(data.instances.iter().map(x).collect::<Vec<_>>(), [0.0, 2.0, 3.0, 4.0]);
+        assert_eq!(dirty(&mut data), [(1, 4)]);
+        // the last one has nothing after it to move
+        data.remove(3);
+        assert!(dirty(&mut data).is_empty());
+    }
+
+    #[test]
+    fn changes_past_a_removal_at_the_end_are_dropped() {
+        let mut data = data(4);
+        data.set(3, &at(-1.0));
+        data.remove(3);
+        data.remove(2);
+        assert!(dirty(&mut data).is_empty());
+        assert_eq!(data.instances.len(), 2);
+    }
+}
@@ -1,20 +1,78 @@
+use std::collections::{BTreeMap, BTreeSet};
+
 use tracing::{error, info, warn};
 use winit::{
-    event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent, MouseButton, DeviceEvent},
+    event::{
+        Event, WindowEvent, DeviceEvent,
+        ModifiersState,
+    },
     event_loop::{ControlFlow, EventLoop},
-    window::{Window, WindowBuilder},
+    window::{CursorGrabMode, Window, WindowBuilder},
 };
 use wgpu::util::DeviceExt;
 use cgmath::prelude::*;
 
-mod texture;
-mod model;
+pub mod texture;
+pub mod model;
 mod resources;
 mod camera;
+mod camera_path;
+mod debug_view;
+mod debug_draw;
+mod transparency;
+mod culling;
+mod gpu_culling;
+mod lod;
+mod picking;
+mod id_picking;
+mod outline;
+mod input;
+// without the gilrs feature there's no way to read gamepads, the bindings
+// to them are still accepted. It's off by default as it needs libudev on
+// Linux, `cargo run --features gilrs` turns it on.
+#[cfg(any(test, feature = "gilrs"))]
+mod gamepad;
+mod replay;
+mod scene;
+mod scene_file;
+mod instance_buffer;
+mod material_array;
 
 use model::{Vertex, DrawModel};
-use camera::{Camera, CameraController, Projection};
-
+use camera::{
+    Camera, CameraControl, CameraController, ControlMode, FlightController, OrbitController,
+    Projection, QuatCamera,
+};
+use camera_path::{CameraPath, CameraPathPlayer};
+use debug_view::{DebugView, RenderMode};
+use debug_draw::DebugDraw;
+use transparency::{BlendedMesh, TransparencySorter};
+use culling::{Frustum, InstanceCuller};
+use gpu_culling::GpuCuller;
+use lod::LodSelector;
+use picking::{Hit, Ray};
+use id_picking::IdPicker;
+use outline::OutlineRenderer;
+use input::{Action, Binding, InputEvent, InputMap};
+#[cfg(feature = "gilrs")]
+use gamepad::{Gamepad, GilrsGamepad};
+use replay::{InputRecorder, InputReplay};
+use scene::{Attachment, NodeId, SceneGraph};
+use scene_file::SceneDescription;
+use instance_buffer::InstanceBuffer;
+use material_array::{DrawMaterialArray, MaterialArray};
+
+
+// where camera paths are saved to and loaded from
+const CAMERA_PATH_FILE: &str = "camera_path.txt";
+// rebinds actions, the defaults are used without it
+const INPUT_CONFIG_FILE: &str = "input.cfg";
+// loaded at startup and saved back to, the built in scene is used without it
+const SCENE_FILE: &str = "scene.ron";
+// how far the shift_lens action moves the view up, as a fraction of its height
+const LENS_SHIFT: f32 = 0.25;
+// sizes orthographic views when the camera isn't looking at anything
+const DEFAULT_FOCUS_DISTANCE: f32 = 10.0;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
@@ -33,10 +91,15 @@ impl CameraUniform {
         }
     }
 
-    fn update_view_proj(&mut self, camera: &Camera, projection: &camera::Projection) {
+    fn update_view_proj(
+        &mut self,
+        position: cgmath::Point3<f32>,
+        view: cgmath::Matrix4<f32>,
+        projection: &camera::Projection,
+    ) {
         // using Vector4 because of uniforms 16 byte spacing requirement
-        self.view_position = camera.position.to_homogeneous().into();
-        self.view_proj = (projection.calc_matrix() * camera.calc_matrix()).into();
+        self.view_position = position.to_homogeneous().into();
+        self.view_proj = (projection.calc_matrix() * view).into();
     }
 }
 
@@ -45,9 +108,25 @@ impl CameraUniform {
 struct InstanceRaw {
     model: [[f32; 4]; 4],
     normal: [[f32; 3]; 3],
+    tint: [f32; 3],
+    // NO_MATERIAL for each mesh's own
+    material: u32,
 }
 
+// an InstanceRaw material for instances that keep their meshes' materials
+const NO_MATERIAL: u32 = u32::MAX;
+
 impl InstanceRaw {
+    // What a mesh with `mesh_material` is drawn with for this instance.
+    // Materials that don't exist fall back to the mesh's, as in
+    // gpu_culling.wgsl.
+    fn material_for(&self, mesh_material: usize, material_count: usize) -> usize {
+        match self.material as usize {
+            material if material < material_count => material,
+            _ => mesh_material,
+        }
+    }
+
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         use std::mem;
         
@@ -100,23 +179,98 @@ impl InstanceRaw {
                     offset: mem::size_of::<[f32; 22]>() as wgpu::BufferAddress,
                     shader_location: 11,
                 },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Float32x3,
+                    offset: mem::size_of::<[f32; 25]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                },
+                wgpu::VertexAttribute {
+                    format: wgpu::VertexFormat::Uint32,
+                    offset: mem::size_of::<[f32; 28]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                },
             ]
         }
     }
 }
 
+// Scaled along its own axes, then rotated, then moved, then carried along by
+// whatever it's placed in. The tint multiplies the colour of its surface.
+#[derive(Debug, Clone)]
 struct Instance {
     position: cgmath::Vector3<f32>,
     rotation: cgmath::Quaternion<f32>,
+    scale: cgmath::Vector3<f32>,
+    tint: [f32; 3],
+    // index into the model's materials, drawn with instead of each mesh's own
+    material: Option<usize>,
+    // the world transform of its parent, identity for anything that isn't
+    // placed in something. Kept whole, since an unevenly scaled parent shears
+    // rotated children, which position, rotation and scale can't hold.
+    parent: cgmath::Matrix4<f32>,
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            position: cgmath::Vector3::zero(),
+            rotation: cgmath::Quaternion::one(),
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            tint: [1.0; 3],
+            material: None,
+            parent: cgmath::Matrix4::identity(),
+        }
+    }
 }
 
 impl Instance {
+    // model space to world space
+    fn matrix(&self) -> cgmath::Matrix4<f32> {
+        self.parent
+            * cgmath::Matrix4::from_translation(self.position)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    fn world_position(&self) -> cgmath::Vector3<f32> {
+        self.matrix().w.truncate()
+    }
+
+    // the world orientation with scale and shear taken out, x kept as it is
+    // and y and z squared up to it
+    fn world_rotation(&self) -> cgmath::Quaternion<f32> {
+        let matrix = self.matrix();
+        let x = matrix.x.truncate().normalize();
+        let y = matrix.y.truncate();
+        let y = (y - x * x.dot(y)).normalize();
+        cgmath::Quaternion::from(cgmath::Matrix3::from_cols(x, y, x.cross(y)))
+    }
+
     fn to_raw(&self) -> InstanceRaw {
-        let model = cgmath::Matrix4::from_translation(self.position) * 
-            cgmath::Matrix4::from(self.rotation);
+        let model = self.matrix();
+        let linear = cgmath::Matrix3::from_cols(
+            model.x.truncate(),
+            model.y.truncate(),
+            model.z.truncate(),
+        );
+        // Stretching a surface tilts its normals the other way, so they take
+        // the inverse-transpose. Shaders renormalise them afterwards. A zero
+        // scale flattens the instance and leaves nothing to invert.
+        let normal = linear.invert()
+            .map_or(cgmath::Matrix3::from(self.rotation), |inverse| inverse.transpose());
         InstanceRaw {
             model: model.into(),
-            normal: cgmath::Matrix3::from(self.rotation).into(),
+            normal: normal.into(),
+            tint: self.tint,
+            material: self.material.map_or(NO_MATERIAL, |material| material as u32),
+        }
+    }
+
+    // This transform carried along by `parent`. The look isn't inherited.
+    fn within(&self, parent: &Instance) -> Instance {
+        Instance {
+            parent: parent.matrix(),
+            ..self.clone()
         }
     }
 }
@@ -140,14 +294,31 @@ struct State {
     size: winit::dpi::PhysicalSize<u32>,
     clear_color: wgpu::Color,
     render_pipeline: wgpu::RenderPipeline,
+    cutout_render_pipeline: wgpu::RenderPipeline,
+    transparent_render_pipeline: wgpu::RenderPipeline,
+    transparency_sorter: TransparencySorter,
     camera: Camera,
     projection: Projection,
     camera_controller: CameraController,
+    orbit_controller: OrbitController,
+    flight_controller: FlightController,
+    camera_path: CameraPathPlayer,
+    control_mode: ControlMode,
     camera_uniform: CameraUniform,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
+    scene: SceneGraph,
+    // what the scene was loaded from, saved back with the scene's nodes
+    scene_description: SceneDescription,
+    // the scene's model instances, as of its last update
     instances: Vec<Instance>,
-    instance_buffer: wgpu::Buffer,
+    // instances on the GPU, read by the ID picker and GPU culler
+    instance_buffer: InstanceBuffer,
+    instance_culler: InstanceCuller,
+    // None when the adapter can't run compute shaders or indirect draws
+    gpu_culler: Option<GpuCuller>,
+    use_gpu_culling: bool,
+    lod_selector: LodSelector,
     depth_texture: texture::Texture,
     obj_model: model::Model,
     light_uniform: LightUniform,
@@ -155,7 +326,72 @@ struct State {
     light_bind_group: wgpu::BindGroup,
     light_render_pipeline: wgpu::RenderPipeline,
     debug_material: model::Material,
-    mouse_pressed: bool,
+    // Draw every mesh with debug_material instead of its own. On at the
+    // start, so the meshes' and instances' own materials only show once
+    // it's toggled off.
+    use_debug_material: bool,
+    // None when the adapter can't read storage buffers in shaders
+    material_array: Option<MaterialArray>,
+    // Draw opaque and cutout meshes from material_array, picking instance
+    // materials in the shader. On at the start, toggled off instances with
+    // their own materials are drawn in batches that bind them one at a time.
+    use_material_array: bool,
+    debug_view: DebugView,
+    debug_draw: DebugDraw,
+    input_map: InputMap,
+    // None when gilrs couldn't start
+    #[cfg(feature = "gilrs")]
+    gamepad: Option<Gamepad>,
+    // held actions that turn mouse movement into looking or panning
+    looking: bool,
+    panning: bool,
+    // looks around with every mouse movement, with the cursor grabbed and
+    // hidden
+    mouse_look: bool,
+    quit_requested: bool,
+    cursor_position: winit::dpi::PhysicalPosition<f64>,
+    // what the last right click landed on
+    picked: Option<Hit>,
+    id_picker: IdPicker,
+    // pick with the ID buffer rather than testing every triangle
+    use_gpu_picking: bool,
+    // the ray under the cursor when the ID buffer pick was asked for
+    gpu_pick_ray: Option<Ray>,
+    // indices into instances
+    selection: BTreeSet<usize>,
+    outline: OutlineRenderer,
+    modifiers: ModifiersState,
+}
+
+// knobs for pipelines that need to differ from the default lit, filled,
+// back-face culled triangle pipeline
+struct PipelineOptions<'a> {
+    vs_entry_point: &'a str,
+    fs_entry_point: &'a str,
+    topology: wgpu::PrimitiveTopology,
+    polygon_mode: wgpu::PolygonMode,
+    depth_write_enabled: bool,
+    depth_compare: wgpu::CompareFunction,
+    // None for formats that can't blend, like integer ones
+    blend: Option<wgpu::BlendState>,
+    write_mask: wgpu::ColorWrites,
+    stencil: wgpu::StencilState,
+}
+
+impl Default for PipelineOptions<'_> {
+    fn default() -> Self {
+        Self {
+            vs_entry_point: "vs_main",
+            fs_entry_point: "fs_main",
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            depth_write_enabled: true,
+            depth_compare: texture::Texture::DEPTH_COMPARE,
+            blend: Some(wgpu::BlendState::REPLACE),
+            write_mask: wgpu::ColorWrites::ALL,
+            stencil: wgpu::StencilState::default(),
+        }
+    }
 }
 
 fn create_render_pipeline(
@@ -165,9 +401,37 @@ fn create_render_pipeline(
     vertex_layouts: &[wgpu::VertexBufferLayout],
     color_format: wgpu::TextureFormat,
     depth_format: Option<wgpu::TextureFormat>,
+) -> wgpu::RenderPipeline {
+    create_render_pipeline_with_options(
+        device,
+        shader,
+        layout,
+        vertex_layouts,
+        color_format,
+        depth_format,
+        PipelineOptions::default(),
+    )
+}
+
+fn create_render_pipeline_with_options(
+    device: &wgpu::Device,
+    shader: wgpu::ShaderModuleDescriptor,
+    layout: &wgpu::PipelineLayout,
+    vertex_layouts: &[wgpu::VertexBufferLayout],
+    color_format: wgpu::TextureFormat,
+    depth_format: Option<wgpu::TextureFormat>,
+    options: PipelineOptions,
 ) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(shader);
 
+    // culling only makes sense for filled triangles
+    let cull_mode = match (options.topology, options.polygon_mode) {
+        (wgpu::PrimitiveTopology::TriangleList, wgpu::PolygonMode::Fill) => {
+            Some(wgpu::Face::Back)
+        }
+        _ => None,
+    };
+
     device.create_render_pipeline(
         &wgpu::RenderPipelineDescriptor { 
             label: Some("render Pipeline"), 
@@ -175,35 +439,32 @@ fn create_render_pipeline(
             vertex: wgpu::VertexState {
 
                 module: &shader,
-                entry_point: "vs_main",
+                entry_point: options.vs_entry_point,
                 buffers: vertex_layouts,
             }, 
             fragment: Some(wgpu::FragmentState { 
                 module: &shader, 
-                entry_point: "fs_main", 
+                entry_point: options.fs_entry_point, 
                 targets: &[Some(wgpu::ColorTargetState {
                     format: color_format,
-                    blend: Some(wgpu::BlendState {
-                        alpha: wgpu::BlendComponent::REPLACE,
-                        color: wgpu::BlendComponent::REPLACE,
-                    }),
-                    write_mask: wgpu::ColorWrites::ALL,
+                    blend: options.blend,
+                    write_mask: options.write_mask,
                 })], 
             }),
             primitive: wgpu::PrimitiveState { 
-                topology: wgpu::PrimitiveTopology::TriangleList, 
+                topology: options.topology, 
                 strip_index_format: None, 
                 front_face: wgpu::FrontFace::Ccw, 
-                cull_mode: Some(wgpu::Face::Back), 
+                cull_mode, 
                 unclipped_depth: false, 
-                polygon_mode: wgpu::PolygonMode::Fill, 
+                polygon_mode: options.polygon_mode, 
                 conservative: false 
             }, 
             depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
                 format,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
+                depth_write_enabled: options.depth_write_enabled,
+                depth_compare: options.depth_compare,
+                stencil: options.stencil,
                 bias: wgpu::DepthBiasState::default(),
             }), 
             multisample: wgpu::MultisampleState { 
@@ -243,7 +504,7 @@ impl State {
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
+                    features: adapter.features() & (DebugView::FEATURES | MaterialArray::FEATURES),
                     // WebGL doesn't support all of wgpu's features, so if
                     // we're building for the web we'll have to disable some.
                     limits: if cfg!(target_arch = "wasm32") {
@@ -301,6 +562,16 @@ impl State {
                         ),
                         count: None,
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer { 
+                            ty: wgpu::BufferBindingType::Uniform, 
+                            has_dynamic_offset: false, 
+                            min_binding_size: None, 
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("texture_bind_group_layout"),
             }
@@ -315,11 +586,25 @@ impl State {
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
         };
 
-        let camera = Camera::new(
-            (0.0, 5.0, 10.0), 
-            cgmath::Deg(-90.0),
-            cgmath::Deg(-20.0)
-        );
+        //------------- SCENE --------------
+        let (scene_description, scene) = load_scene();
+        let instances = scene.instances();
+
+        // the view starts from the scene's first camera
+        let camera = scene.attached(Attachment::Camera)
+            .next()
+            .map(|node| {
+                let world = scene.world(node);
+                Camera::from(&QuatCamera {
+                    position: cgmath::Point3::from_vec(world.world_position()),
+                    orientation: world.world_rotation(),
+                })
+            })
+            .unwrap_or_else(|| Camera::new(
+                (0.0, 5.0, 10.0), 
+                cgmath::Deg(-90.0),
+                cgmath::Deg(-20.0)
+            ));
 
         let projection = camera::Projection::new(
             config.width,
@@ -334,9 +619,15 @@ impl State {
             0.4
         );
 
+        let orbit_controller = OrbitController::new(
+            (0.0, 0.0, 0.0),
+            10.0,
+            0.4
+        );
+
         let mut camera_uniform = CameraUniform::new();
         
-        camera_uniform.update_view_proj(&camera, &projection);
+        camera_uniform.update_view_proj(camera.position, camera.calc_matrix(), &projection);
 
         let camera_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
@@ -378,69 +669,13 @@ impl State {
             }
         );
 
-        //------------- INSTANCES --------------
-        const NUM_INSTANCES_PER_ROW: u32 = 10;
-        const SPACE_BETWEEN: f32 = 3.0;
-        let instances = (0..NUM_INSTANCES_PER_ROW).flat_map(
-            |z| {
-                (0..NUM_INSTANCES_PER_ROW).map(
-                    move |x| {
-                        let x = SPACE_BETWEEN * (
-                            x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0
-                        );
-                        let z = SPACE_BETWEEN * (
-                            z as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0
-                        );                        
-
-                        let position = cgmath::Vector3 {
-                            x: x,
-                            y: 0.0,
-                            z: z,
-                        };
-
-                        /* let rotation = if position.is_zero() {
-                            // this is needed so an object at (0, 0, 0) won't get scaled to zero
-                            // as Quaternions can effect scale if they're not created correctly
-                            cgmath::Quaternion::from_axis_angle(
-                                cgmath::Vector3::unit_z(), 
-                                cgmath::Deg(0.0)
-                            )
-                        } else {
-                            cgmath::Quaternion::from_axis_angle(
-                                position.normalize(), 
-                                cgmath::Deg(45.0)
-                            )
-                        }; */
-
-                        let rotation = cgmath::Quaternion::from_axis_angle(
-                            (0.0, 1.0, 0.0).into(), 
-                            cgmath::Deg(180.0)
-                        );
-
-                        Instance {
-                            position,
-                            rotation,
-                        }
-                    }
-                )
-            }
-        ).collect::<Vec<_>>();
-
-        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
-        
-        let instance_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Instance Buffer"),
-                contents: bytemuck::cast_slice(&instance_data),
-                usage: wgpu::BufferUsages::VERTEX,
-            }
-        );
+        let instance_culler = InstanceCuller::new(&device);
 
         //------------- creating buffer to store light in ----------------
         let light_uniform = LightUniform {
-            position: [2.0, 2.0, 2.0],
+            position: scene_light_position(&scene).into(),
             _padding: 0,
-            color: [1.0, 1.0, 1.0],
+            color: scene_description.light_color,
             _padding2: 0,
         };
 
@@ -502,10 +737,16 @@ impl State {
             "depth_texture"
         );
 
+        // lit.wgsl is shared with the material array's shader
+        let shader_source = format!(
+            "{}\n{}",
+            include_str!("lit.wgsl"),
+            include_str!("shader.wgsl"),
+        );
         let render_pipeline = {
             let shader = wgpu::ShaderModuleDescriptor {
                 label: Some("Normal Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
+                source: wgpu::ShaderSource::Wgsl(shader_source.as_str().into()),
             };
             create_render_pipeline(
                 &device, 
@@ -517,6 +758,49 @@ impl State {
             )
         };
 
+        let cutout_render_pipeline = {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: Some("Cutout Shader"),
+                source: wgpu::ShaderSource::Wgsl(shader_source.as_str().into()),
+            };
+            create_render_pipeline_with_options(
+                &device, 
+                shader, 
+                &render_pipeline_layout, 
+                &[model::ModelVertex::desc(), InstanceRaw::desc()], 
+                config.format, 
+                Some(texture::Texture::DEPTH_FORMAT),
+                PipelineOptions {
+                    fs_entry_point: "fs_cutout",
+                    ..Default::default()
+                },
+            )
+        };
+
+        // blended geometry is tested against, but doesn't write, depth so
+        // everything behind it still shows through
+        let transparent_render_pipeline = {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: Some("Transparent Shader"),
+                source: wgpu::ShaderSource::Wgsl(shader_source.as_str().into()),
+            };
+            create_render_pipeline_with_options(
+                &device, 
+                shader, 
+                &render_pipeline_layout, 
+                &[model::ModelVertex::desc(), InstanceRaw::desc()], 
+                config.format, 
+                Some(texture::Texture::DEPTH_FORMAT),
+                PipelineOptions {
+                    depth_write_enabled: false,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    ..Default::default()
+                },
+            )
+        };
+
+        let transparency_sorter = TransparencySorter::new(&device);
+
         let light_render_pipeline = {
             let layout = device.create_pipeline_layout(
                 &wgpu::PipelineLayoutDescriptor {
@@ -542,14 +826,80 @@ impl State {
             )
         };
 
+        let debug_view = DebugView::new(
+            &device,
+            &render_pipeline_layout,
+            config.format,
+        );
+
+        let debug_draw = DebugDraw::new(
+            &device,
+            &camera_bind_group_layout,
+            config.format,
+        );
+
         surface.configure(&device, &config); 
 
-        let obj_model = resources::load_model(
-            "cube.obj", 
+        let mut obj_model = resources::load_model(
+            &scene_description.model, 
             &device, 
             &queue, 
             &texture_bind_group_layout
         ).await;
+        let mut materials = Vec::new();
+        for material in std::mem::take(&mut obj_model.materials) {
+            let description = scene_description.materials.iter()
+                .find(|description| description.name == material.name);
+            materials.push(match description {
+                Some(description) => {
+                    description.apply(material, &device, &queue, &texture_bind_group_layout).await
+                }
+                None => material,
+            });
+        }
+        obj_model.materials = materials;
+
+        let gpu_culling_supported = GpuCuller::is_supported(&adapter);
+        let mut instance_buffer = InstanceBuffer::new(
+            &device,
+            if gpu_culling_supported {
+                wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE
+            } else {
+                wgpu::BufferUsages::VERTEX
+            },
+            &instances,
+        );
+        instance_buffer.upload(&device, &queue);
+
+        let gpu_culler = if gpu_culling_supported {
+            Some(GpuCuller::new(&device, &obj_model, &instance_buffer))
+        } else {
+            warn!("compute shaders or indirect draws unsupported, culling on the CPU only");
+            None
+        };
+
+        let id_picker = IdPicker::new(
+            &device,
+            &config,
+            [&texture_bind_group_layout, &camera_bind_group_layout, &light_bind_group_layout],
+            &obj_model,
+        );
+
+        let input_map = match InputMap::load(INPUT_CONFIG_FILE) {
+            Ok(input_map) => input_map,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("couldn't load input config: {}", e);
+                }
+                InputMap::default()
+            }
+        };
+
+        let outline = OutlineRenderer::new(
+            &device,
+            &config,
+            [&texture_bind_group_layout, &camera_bind_group_layout, &light_bind_group_layout],
+        );
 
         let debug_material = {
             let diffuse_bytes = include_bytes!("../res/cobble-diffuse.png");
@@ -575,10 +925,29 @@ impl State {
                 "alt-material", 
                 diffuse_texture, 
                 normal_texture, 
+                model::AlphaMode::Opaque,
+                1.0,
                 &texture_bind_group_layout
             )
         };
 
+        let material_array = if MaterialArray::is_supported(&device) {
+            let material_array = MaterialArray::new(
+                &device,
+                &queue,
+                config.format,
+                &camera_bind_group_layout,
+                &light_bind_group_layout,
+                &obj_model,
+                &debug_material,
+            );
+            info!("material textures in {:?}", material_array.texture_layout());
+            Some(material_array)
+        } else {
+            warn!("storage buffers unsupported, materials are bound one at a time");
+            None
+        };
+
         Self {
             surface,
             device,
@@ -587,14 +956,27 @@ impl State {
             size,
             clear_color,
             render_pipeline,
+            cutout_render_pipeline,
+            transparent_render_pipeline,
+            transparency_sorter,
             camera,
             projection,
             camera_controller,
+            orbit_controller,
+            flight_controller: FlightController::new(4.0, 0.4),
+            camera_path: CameraPathPlayer::new(0.25),
+            control_mode: ControlMode::Fly,
             camera_uniform,
             camera_buffer,
             camera_bind_group,
+            scene,
+            scene_description,
             instances,
             instance_buffer,
+            instance_culler,
+            gpu_culler,
+            use_gpu_culling: false,
+            lod_selector: LodSelector::new(0.1),
             depth_texture,
             obj_model,
             light_uniform,
@@ -602,7 +984,32 @@ impl State {
             light_bind_group,
             light_render_pipeline,
             debug_material,
-            mouse_pressed: false,
+            use_debug_material: true,
+            material_array,
+            use_material_array: true,
+            debug_view,
+            debug_draw,
+            input_map,
+            #[cfg(feature = "gilrs")]
+            gamepad: match GilrsGamepad::new() {
+                Ok(device) => Some(Gamepad::new(Box::new(device))),
+                Err(e) => {
+                    warn!("no gamepads: {}", e);
+                    None
+                }
+            },
+            looking: false,
+            panning: false,
+            mouse_look: false,
+            quit_requested: false,
+            cursor_position: winit::dpi::PhysicalPosition::new(0.0, 0.0),
+            picked: None,
+            id_picker,
+            use_gpu_picking: false,
+            gpu_pick_ray: None,
+            selection: BTreeSet::new(),
+            outline,
+            modifiers: ModifiersState::empty(),
         }
     }
 
@@ -619,55 +1026,440 @@ impl State {
             &self.config, 
             "depth_texture"
         );
+        self.id_picker.resize(&self.device, &self.config);
+        self.outline.resize(&self.device, &self.config);
     }
 
-    fn input(&mut self, event: &WindowEvent) -> bool {
+    fn active_controller(&mut self) -> &mut dyn CameraControl {
+        match self.control_mode {
+            ControlMode::Fly => &mut self.camera_controller,
+            ControlMode::Orbit => &mut self.orbit_controller,
+            ControlMode::Flight => &mut self.flight_controller,
+        }
+    }
+
+    fn toggle_control_mode(&mut self) {
+        self.control_mode = match self.control_mode {
+            ControlMode::Fly => {
+                self.orbit_controller.look_from(&self.camera);
+                ControlMode::Orbit
+            }
+            ControlMode::Orbit => {
+                self.flight_controller.look_from(&self.camera);
+                ControlMode::Flight
+            }
+            // the fly camera picks up from wherever the others left it
+            ControlMode::Flight => ControlMode::Fly,
+        };
+        info!("camera mode: {:?}", self.control_mode);
+    }
+
+    // the camera as shown, including the flight camera's roll
+    fn view_camera(&self) -> QuatCamera {
+        match self.control_mode {
+            ControlMode::Flight => *self.flight_controller.camera(),
+            _ => QuatCamera::from(&self.camera),
+        }
+    }
+
+    // how far away what's in the middle of the screen is, from the active
+    // controller when it knows, otherwise whatever a ray through the middle
+    // hits
+    fn focus_distance(&self) -> f32 {
+        let controller: &dyn CameraControl = match self.control_mode {
+            ControlMode::Fly => &self.camera_controller,
+            ControlMode::Orbit => &self.orbit_controller,
+            ControlMode::Flight => &self.flight_controller,
+        };
+        controller.focus_distance().unwrap_or_else(|| {
+            let camera = self.view_camera();
+            let centre = winit::dpi::PhysicalPosition::new(
+                self.size.width as f64 * 0.5,
+                self.size.height as f64 * 0.5,
+            );
+            Ray::from_screen(centre, self.size, camera.calc_matrix(), &self.projection)
+                .and_then(|ray| picking::pick(&ray, &self.instances, &self.obj_model.meshes))
+                .map_or(DEFAULT_FOCUS_DISTANCE, |hit| hit.point.distance(camera.position))
+        })
+    }
+
+    fn toggle_camera_recording(&mut self) {
+        if self.camera_path.is_recording() {
+            let camera = self.view_camera();
+            self.camera_path.stop_recording(&camera);
+            match self.camera_path.path.save(CAMERA_PATH_FILE) {
+                Ok(()) => info!("saved camera path to {}", CAMERA_PATH_FILE),
+                Err(e) => warn!("couldn't save camera path: {}", e),
+            }
+        } else if !self.camera_path.is_playing() {
+            info!("recording camera path");
+            self.camera_path.start_recording();
+        }
+    }
+
+    fn toggle_camera_playback(&mut self) {
+        if self.camera_path.is_playing() {
+            self.camera_path.stop_playback();
+        } else if !self.camera_path.is_recording() {
+            // pick up a path saved by an earlier run
+            if self.camera_path.path.is_empty() {
+                match CameraPath::load(CAMERA_PATH_FILE) {
+                    Ok(path) => self.camera_path.path = path,
+                    Err(e) => warn!("couldn't load camera path: {}", e),
+                }
+            }
+            info!("playing camera path");
+            self.camera_path.start_playback();
+        }
+    }
+
+    // the centre of the window in mouse look, where the cursor would be
+    // otherwise
+    fn pick_position(&self) -> winit::dpi::PhysicalPosition<f64> {
+        if self.mouse_look {
+            winit::dpi::PhysicalPosition::new(
+                self.size.width as f64 * 0.5,
+                self.size.height as f64 * 0.5,
+            )
+        } else {
+            self.cursor_position
+        }
+    }
+
+    fn pick(&mut self) {
+        let position = self.pick_position();
+        let ray = Ray::from_screen(
+            position,
+            self.size,
+            self.view_camera().calc_matrix(),
+            &self.projection,
+        );
+        if self.use_gpu_picking {
+            // finishes in update once the ID buffer has been read back
+            if self.id_picker.request(position.cast::<u32>()) {
+                self.gpu_pick_ray = ray;
+            }
+            return;
+        }
+        self.picked = ray.and_then(|ray| {
+            picking::pick(&ray, &self.instances, &self.obj_model.meshes)
+        });
+        self.finish_pick();
+    }
+
+    fn finish_gpu_pick(&mut self, picked: Option<id_picking::PickedId>) {
+        // the ID buffer says what was hit, the ray finds where
+        self.picked = picked.zip(self.gpu_pick_ray.take()).and_then(|(picked, ray)| {
+            picking::pick_mesh(
+                &ray,
+                &self.instances,
+                &self.obj_model.meshes,
+                picked.instance,
+                picked.mesh,
+            )
+        });
+        self.finish_pick();
+    }
+
+    // picking selects, with ctrl held it adds to or removes from the
+    // selection instead
+    fn finish_pick(&mut self) {
+        let add = self.modifiers.ctrl();
+        match &self.picked {
+            Some(hit) => {
+                info!(
+                    "picked instance {} mesh {} at {:?}, {} away",
+                    hit.instance, hit.mesh, hit.point, hit.distance,
+                );
+                if !add {
+                    self.selection.clear();
+                }
+                if !self.selection.remove(&hit.instance) {
+                    self.selection.insert(hit.instance);
+                }
+            }
+            None => {
+                info!("picked nothing");
+                if !add {
+                    self.selection.clear();
+                }
+            }
+        }
+    }
+
+    fn process_binding(&mut self, binding: Binding, pressed: bool) {
+        let actions = self.input_map.actions(binding).collect::<Vec<_>>();
+        for action in actions {
+            self.handle_action(action, pressed);
+        }
+    }
+
+    fn handle_action(&mut self, action: Action, pressed: bool) {
+        match action {
+            Action::Look => self.looking = pressed,
+            Action::Pan => self.panning = pressed,
+            Action::ToggleMouseLook if pressed => {
+                self.mouse_look = !self.mouse_look;
+                info!("mouse look: {}", self.mouse_look);
+            }
+            _ if self.active_controller().process_action(action, pressed) => {}
+            // the rest happen once, when pressed
+            _ if !pressed => {}
+            Action::Pick => self.pick(),
+            Action::SwitchCamera => self.toggle_control_mode(),
+            Action::ToggleProjection => {
+                let focus_distance = self.focus_distance();
+                self.projection.toggle_orthographic(focus_distance);
+                info!("projection: {:?}", self.projection.kind());
+            }
+            Action::ShiftLens => {
+                self.projection.toggle_lens_shift(LENS_SHIFT);
+                info!("projection: {:?}", self.projection.kind());
+            }
+            Action::ToggleOblique => {
+                let focus_distance = self.focus_distance();
+                self.projection.toggle_oblique(focus_distance);
+                info!("projection: {:?}", self.projection.kind());
+            }
+            Action::RecordPath => self.toggle_camera_recording(),
+            Action::PlayPath => self.toggle_camera_playback(),
+            Action::ToggleGpuPicking => {
+                self.use_gpu_picking = !self.use_gpu_picking;
+                info!("gpu picking: {}", self.use_gpu_picking);
+            }
+            Action::ToggleGpuCulling => {
+                self.use_gpu_culling = !self.use_gpu_culling;
+                info!("gpu culling: {}", self.use_gpu_culling);
+            }
+            Action::ToggleDebugDraw => self.debug_draw.enabled = !self.debug_draw.enabled,
+            Action::ToggleDebugMaterial => {
+                self.use_debug_material = !self.use_debug_material;
+            }
+            Action::ToggleMaterialArray => {
+                self.use_material_array = !self.use_material_array;
+                info!("material array: {}", self.use_material_array);
+            }
+            Action::SpawnInstance => {
+                // so spawned instances can be told apart
+                const TINTS: [[f32; 3]; 4] = [
+                    [1.0, 0.5, 0.5],
+                    [0.5, 1.0, 0.5],
+                    [0.5, 0.5, 1.0],
+                    [1.0, 1.0, 0.5],
+                ];
+                let camera = self.view_camera();
+                let mut count = 0;
+                let name = loop {
+                    count += 1;
+                    let name = format!("spawned {}", count);
+                    if self.scene.find(&name).is_none() {
+                        break name;
+                    }
+                };
+                info!("spawned {:?}", name);
+                self.add_instance(name, None, Instance {
+                    position: camera.position.to_vec() + camera.forward() * 5.0,
+                    tint: TINTS[(count - 1) % TINTS.len()],
+                    ..Default::default()
+                });
+            }
+            Action::RemoveSelection => {
+                let nodes = self.selection.iter()
+                    .map(|&index| self.scene.instance_node(index))
+                    .collect::<Vec<_>>();
+                for node in nodes {
+                    // already gone if it was below another selected instance
+                    if self.scene.contains(node) {
+                        self.remove_instance(node);
+                    }
+                }
+            }
+            Action::RaiseSelection => self.move_selection(cgmath::Vector3::unit_y() * 0.5),
+            Action::LowerSelection => self.move_selection(cgmath::Vector3::unit_y() * -0.5),
+            Action::SaveScene => {
+                self.scene_description.capture(&self.scene);
+                match self.scene_description.save(SCENE_FILE) {
+                    Ok(()) => info!("saved scene to {}", SCENE_FILE),
+                    Err(e) => warn!("couldn't save scene to {}: {}", SCENE_FILE, e),
+                }
+            }
+            Action::Quit => self.quit_requested = true,
+            // the render_* actions pick a debug view, anything else is
+            // movement the active controller has no use for, or zoom, which
+            // only scrolling drives
+            _ => {
+                if let Some(mode) = RenderMode::from_action(action) {
+                    self.debug_view.set_mode(mode);
+                }
+            }
+        }
+    }
+
+    // A new instance of the model placed relative to `parent`, it's in
+    // place from the next update
+    fn add_instance(&mut self, name: String, parent: Option<NodeId>, local: Instance) -> NodeId {
+        let node = self.scene.add(name, parent, local.clone());
+        self.scene.attach(node, Attachment::Model);
+        self.instance_buffer.push(&local);
+        self.instances.push(local);
+        node
+    }
+
+    // Takes the node and everything below it out of the scene. Later
+    // instances move down a place for each instance removed, the selection
+    // and pick follow them.
+    fn remove_instance(&mut self, node: NodeId) {
+        let removed = self.scene.remove(node);
+        for &index in removed.iter().rev() {
+            self.instances.remove(index);
+            self.instance_buffer.remove(index);
+            self.lod_selector.remove_instance(index);
+        }
+        let moved_to = |index: usize| match removed.binary_search(&index) {
+            Ok(_) => None,
+            Err(below) => Some(index - below),
+        };
+        self.selection = self.selection.iter()
+            .filter_map(|&index| moved_to(index))
+            .collect();
+        self.picked = self.picked.take().and_then(|mut hit| {
+            hit.instance = moved_to(hit.instance)?;
+            Some(hit)
+        });
+    }
+
+    // moves the node relative to its parent, it's in place from the next
+    // update
+    fn update_instance(&mut self, node: NodeId, local: Instance) {
+        self.scene.set_local(node, local);
+    }
+
+    // by `offset` in each selected instance's parent's space
+    fn move_selection(&mut self, offset: cgmath::Vector3<f32>) {
+        let nodes = self.selection.iter()
+            .map(|&index| self.scene.instance_node(index))
+            .collect::<Vec<_>>();
+        for node in nodes {
+            let mut local = self.scene.local(node).clone();
+            local.position += offset;
+            self.update_instance(node, local);
+        }
+    }
+
+    fn process_mouse_motion(&mut self, mouse_dx: f64, mouse_dy: f64) {
+        if self.looking || self.mouse_look {
+            self.active_controller().process_mouse(mouse_dx, mouse_dy);
+        } else if self.panning {
+            self.active_controller().process_pan(mouse_dx, mouse_dy);
+        }
+    }
+
+    fn input(&mut self, event: InputEvent) {
         match event {
-            WindowEvent::CursorMoved {
-                position,
-                ..
-            } => {
+            InputEvent::CursorMoved(position) => {
+                self.cursor_position = position;
                 let r = position.x / self.size.width as f64;
                 let g = position.y / self.size.height as f64;
                 self.clear_color = wgpu::Color {
-                    r: r,
-                    g: g,
+                    r,
+                    g,
                     b: 0.3,
                     a: 1.0,
                 };
-                true
             }
 
-            WindowEvent::KeyboardInput { 
-                input: KeyboardInput {
-                    virtual_keycode: Some(key),
-                    state,
-                    ..
-                }, 
-                ..
-            } => self.camera_controller.process_keyboard(*key, *state),
+            InputEvent::Key(key, pressed) => self.process_binding(Binding::Key(key), pressed),
 
-            WindowEvent::MouseWheel { delta, ..} => {
-                self.camera_controller.process_scroll(delta);
-                true
+            InputEvent::Modifiers(modifiers) => self.modifiers = modifiers,
+
+            InputEvent::Scroll(delta) => {
+                let actions = self.input_map.actions(Binding::Scroll).collect::<Vec<_>>();
+                for action in actions {
+                    if action == Action::Zoom {
+                        if !self.projection.zoom(&delta) {
+                            self.active_controller().process_scroll(&delta);
+                        }
+                    } else {
+                        // a scroll is a press and release of anything else
+                        self.handle_action(action, true);
+                        self.handle_action(action, false);
+                    }
+                }
             }
 
-            WindowEvent::MouseInput { 
-                state, 
-                button: MouseButton::Left, 
-                .. 
-            } => {
-                self.mouse_pressed = *state == ElementState::Pressed;
-                true
+            InputEvent::MouseButton(button, pressed) => {
+                self.process_binding(Binding::Mouse(button), pressed);
+            }
+
+            InputEvent::MouseMotion(mouse_dx, mouse_dy) => {
+                self.process_mouse_motion(mouse_dx, mouse_dy);
             }
 
-            _ => false
+            // the cursor can't stay grabbed when switching away
+            InputEvent::Focused(false) => self.mouse_look = false,
+
+            InputEvent::Focused(true) => {}
+
+            InputEvent::GamepadButton(button, pressed) => {
+                self.process_binding(Binding::Gamepad(button), pressed);
+            }
+
+            InputEvent::GamepadAnalog(analog, amount) => {
+                let actions = self.input_map.actions(Binding::GamepadAnalog(analog))
+                    .collect::<Vec<_>>();
+                for action in actions {
+                    self.active_controller().process_axis(action, amount);
+                }
+            }
+
+            InputEvent::GamepadLook(look_dx, look_dy) => {
+                self.active_controller().process_mouse(look_dx, look_dy);
+            }
         }
     }
 
+    // what the gamepad did since the last frame, as input for State::input
+    #[cfg(feature = "gilrs")]
+    fn poll_gamepad(&mut self, dt: instant::Duration) -> Vec<InputEvent> {
+        let Some(gamepad) = &mut self.gamepad else {
+            return Vec::new();
+        };
+        let mut inputs = gamepad.poll();
+        let (look_dx, look_dy) = gamepad.look(dt.as_secs_f32());
+        if look_dx != 0.0 || look_dy != 0.0 {
+            inputs.push(InputEvent::GamepadLook(look_dx, look_dy));
+        }
+        inputs
+    }
+
     fn update(&mut self, dt: instant::Duration) {
-        self.camera_controller.update_camera(&mut self.camera, dt);
-        self.camera_uniform.update_view_proj(&self.camera, &self.projection);
+        if let Some(picked) = self.id_picker.poll(&self.device) {
+            self.finish_gpu_pick(picked);
+        }
+
+        // a playing camera path overrides the controllers
+        let view = if let Some(played) = self.camera_path.play(dt) {
+            self.camera = Camera::from(&played);
+            // so the controller carries on from here once the path ends
+            match self.control_mode {
+                ControlMode::Fly => {}
+                ControlMode::Orbit => self.orbit_controller.look_from(&self.camera),
+                ControlMode::Flight => self.flight_controller.set_camera(played),
+            }
+            played.calc_matrix()
+        } else {
+            let camera = &mut self.camera;
+            match self.control_mode {
+                ControlMode::Fly => self.camera_controller.update_camera(camera, dt),
+                ControlMode::Orbit => self.orbit_controller.update_camera(camera, dt),
+                ControlMode::Flight => self.flight_controller.update_camera(camera, dt),
+            }
+            // the flight camera can roll, which the yaw/pitch camera can't show
+            let view_camera = self.view_camera();
+            self.camera_path.record(&view_camera, dt);
+            view_camera.calc_matrix()
+        };
+        self.camera_uniform.update_view_proj(self.camera.position, view, &self.projection);
         self.queue.write_buffer(
             &self.camera_buffer,
             0,
@@ -675,21 +1467,169 @@ impl State {
         );
 
         // update the light
-        let old_light_position: cgmath::Vector3<_> = self.light_uniform.position.into();
-        self.light_uniform.position = (
-            cgmath::Quaternion::from_axis_angle(
-                (0.0, 1.0, 0.0).into(),
-                cgmath::Deg(60.0 * dt.as_secs_f32())
-            ) * old_light_position
-        ).into();
+        self.scene.animate(dt.as_secs_f32());
+        for index in self.scene.update() {
+            let instance = self.scene.world(self.scene.instance_node(index)).clone();
+            self.instance_buffer.set(index, &instance);
+            self.instances[index] = instance;
+        }
+        self.instance_buffer.upload(&self.device, &self.queue);
+        if let Some(gpu_culler) = &mut self.gpu_culler {
+            gpu_culler.set_instances(&self.device, &self.instance_buffer);
+        }
+        self.light_uniform.position = scene_light_position(&self.scene).into();
         self.queue.write_buffer(
             &self.light_buffer, 
             0, 
             bytemuck::cast_slice(&[self.light_uniform])
         );
+
+        // show where the light is and which way the world axes point
+        let light_position: cgmath::Point3<_> = self.light_uniform.position.into();
+        let light_extent = cgmath::Vector3::new(0.25, 0.25, 0.25);
+        self.debug_draw.aabb(
+            light_position - light_extent,
+            light_position + light_extent,
+            self.light_uniform.color,
+        );
+        self.debug_draw.depth_test = false;
+        self.debug_draw.sphere(light_position, 0.5, self.light_uniform.color);
+        self.debug_draw.depth_test = true;
+        self.debug_draw.axes(cgmath::Matrix4::identity());
+
+        if let Some(hit) = &self.picked {
+            self.debug_draw.sphere(hit.point, 0.05, [1.0, 1.0, 0.0]);
+        }
+    }
+
+    // what draws with the model's material `index` bind, debug views and the
+    // debug material replace it
+    fn material(&self, index: usize) -> &model::Material {
+        if self.use_debug_material || self.debug_view.pipeline().is_some() {
+            &self.debug_material
+        } else {
+            &self.obj_model.materials[index]
+        }
+    }
+
+    // debug views bind their own
+    fn active_material_array(&self) -> Option<&MaterialArray> {
+        self.material_array.as_ref().filter(|_| {
+            self.use_material_array && self.debug_view.pipeline().is_none()
+        })
+    }
+
+    // None for blended materials, they're drawn sorted after the rest
+    fn opaque_pipeline(&self, material: &model::Material) -> Option<&wgpu::RenderPipeline> {
+        match (self.debug_view.pipeline(), self.active_material_array(), material.alpha_mode) {
+            // debug views draw everything with their own opaque pipeline
+            (Some(pipeline), _, _) => Some(pipeline),
+            (None, _, model::AlphaMode::Blend) => None,
+            (None, Some(material_array), alpha_mode) => Some(material_array.pipeline(alpha_mode)),
+            (None, None, model::AlphaMode::Opaque) => Some(&self.render_pipeline),
+            (None, None, model::AlphaMode::Mask(_)) => Some(&self.cutout_render_pipeline),
+        }
     }
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        self.debug_draw.prepare(&self.device, &self.queue);
+        self.outline.prepare(
+            &self.device,
+            &self.queue,
+            &self.config,
+            &self.instances,
+            &self.selection,
+        );
+
+        if let Some(material_array) = self.active_material_array() {
+            material_array.use_debug_material(&self.queue, self.use_debug_material);
+        }
+
+        let frustum = Frustum::from_matrix(self.camera_uniform.view_proj.into());
+        let mesh_bounds = self.obj_model.meshes.iter()
+            .map(|mesh| mesh.bounds)
+            .collect::<Vec<_>>();
+
+        let instance_models = self.instance_buffer.instances().iter()
+            .map(|raw| raw.model.into())
+            .collect::<Vec<_>>();
+        self.lod_selector.update(
+            &self.obj_model.meshes,
+            &instance_models,
+            self.camera.position,
+            &self.projection,
+        );
+
+        // Edge wireframes need index counts the indirect draws don't carry.
+        // The indirect draws are one per mesh, so instances with their own
+        // materials need the material array to pick them in the shader, and
+        // can't change whether the mesh is cut out or blended. They're also
+        // always full detail, the LOD selection only reaches the CPU culled
+        // draws.
+        let raws = self.instance_buffer.instances();
+        let material_count = self.obj_model.materials.len();
+        let overrides_materials = raws.iter().any(|raw| raw.material != NO_MATERIAL);
+        let alpha_mode = |material| std::mem::discriminant(&self.material(material).alpha_mode);
+        let overrides_alpha_modes = raws.iter().any(|raw| {
+            self.obj_model.meshes.iter().any(|mesh| {
+                alpha_mode(raw.material_for(mesh.material, material_count))
+                    != alpha_mode(mesh.material)
+            })
+        });
+        let gpu_culler = self.gpu_culler.as_ref().filter(|_| {
+            self.use_gpu_culling
+                && !self.debug_view.draws_edges()
+                && (self.active_material_array().is_some() || !overrides_materials)
+                && !overrides_alpha_modes
+        });
+        let all_instances;
+        let visible = if gpu_culler.is_some() {
+            // the GPU decides visibility, blended meshes get sorted unculled
+            all_instances = vec![(0..self.instances.len()).collect::<Vec<_>>(); mesh_bounds.len()];
+            &all_instances
+        } else {
+            self.instance_culler.cull(
+                &self.device,
+                &self.queue,
+                &frustum,
+                raws,
+                &self.obj_model,
+                &self.lod_selector,
+            );
+            &self.instance_culler.visible
+        };
+
+        // The visible instances of each mesh that end up blended, by the
+        // material they're drawn with. Debug views draw everything with their
+        // own opaque pipeline.
+        let mut blended = BTreeMap::<(usize, usize), Vec<usize>>::new();
+        if self.debug_view.pipeline().is_none() {
+            for (mesh_index, mesh) in self.obj_model.meshes.iter().enumerate() {
+                for &i in &visible[mesh_index] {
+                    let material = raws[i].material_for(mesh.material, material_count);
+                    if self.material(material).alpha_mode == model::AlphaMode::Blend {
+                        blended.entry((mesh_index, material)).or_default().push(i);
+                    }
+                }
+            }
+        }
+        let blended_meshes = blended.iter()
+            .map(|(&(mesh, material), visible)| BlendedMesh {
+                mesh,
+                material,
+                center: mesh_bounds[mesh].center(),
+                visible,
+            })
+            .collect::<Vec<_>>();
+        self.transparency_sorter.prepare(
+            &self.device,
+            &self.queue,
+            self.camera.position,
+            raws,
+            &blended_meshes,
+            &self.lod_selector,
+        );
+
         let output = self.surface.get_current_texture()?;
         let view = output
             .texture
@@ -700,6 +1640,10 @@ impl State {
                 label: Some("Render Encoder"),
             });
 
+        if let Some(gpu_culler) = gpu_culler {
+            gpu_culler.cull(&self.queue, &mut encoder, &frustum);
+        }
+
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
@@ -716,7 +1660,7 @@ impl State {
                         view: &self.depth_texture.view,
                         depth_ops: Some(
                             wgpu::Operations {
-                                load: wgpu::LoadOp::Clear(1.0),
+                                load: wgpu::LoadOp::Clear(texture::Texture::DEPTH_CLEAR),
                                 store: true,
                             }
                         ),
@@ -725,7 +1669,8 @@ impl State {
                 ),
             });
 
-            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+            // the GPU culled draws bind their own slice per mesh
+            render_pass.set_vertex_buffer(1, self.instance_culler.instance_buffer().slice(..));
 
             use crate::model::DrawLight;
             render_pass.set_pipeline(&self.light_render_pipeline);
@@ -735,36 +1680,220 @@ impl State {
                 &self.light_bind_group
             );
 
-            render_pass.set_pipeline(&self.render_pipeline);
-            /* render_pass.draw_model_instanced(
-                &self.obj_model, 
-                0..self.instances.len() as u32, 
-                &self.camera_bind_group,
-                &self.light_bind_group,
-            ); */
-            render_pass.draw_model_instanced_with_material(
-                &self.obj_model, 
-                &self.debug_material,
-                0..self.instances.len() as u32, 
-                &self.camera_bind_group,
-                &self.light_bind_group,
-            );
+            if self.debug_view.draws_edges() {
+                use crate::model::DrawEdges;
+                render_pass.set_pipeline(self.debug_view.pipeline().unwrap());
+                render_pass.set_bind_group(0, &self.debug_material.bind_group, &[]);
+                // edges only exist for the full detail LOD
+                for (i, mesh) in self.obj_model.meshes.iter().enumerate() {
+                    let instances = self.instance_culler.mesh_range(i);
+                    if instances.is_empty() {
+                        continue;
+                    }
+                    render_pass.draw_mesh_edges_instanced(
+                        mesh,
+                        instances,
+                        &self.camera_bind_group,
+                        &self.light_bind_group,
+                    );
+                }
+            } else {
+                let material_array = self.active_material_array();
+                if let Some(material_array) = material_array {
+                    render_pass.set_material_array(
+                        material_array,
+                        &self.camera_bind_group,
+                        &self.light_bind_group,
+                    );
+                }
+
+                // opaque and cutout meshes first, in any order
+                for (i, mesh) in self.obj_model.meshes.iter().enumerate() {
+                    match gpu_culler {
+                        // the GPU culled draws are always full detail
+                        Some(gpu_culler) => {
+                            let material = self.material(mesh.material);
+                            let Some(pipeline) = self.opaque_pipeline(material) else {
+                                continue;
+                            };
+                            render_pass.set_pipeline(pipeline);
+                            render_pass.set_vertex_buffer(1, gpu_culler.instance_slice(i));
+                            match material_array {
+                                Some(_) => render_pass.draw_mesh_indirect_from_array(
+                                    mesh,
+                                    gpu_culler.indirect_buffer(),
+                                    GpuCuller::indirect_offset(i),
+                                ),
+                                None => render_pass.draw_mesh_indirect(
+                                    mesh,
+                                    material,
+                                    gpu_culler.indirect_buffer(),
+                                    GpuCuller::indirect_offset(i),
+                                    &self.camera_bind_group,
+                                    &self.light_bind_group,
+                                ),
+                            }
+                        }
+                        // each mesh LOD draws only its own ranges of the
+                        // culled instances, one per material
+                        None => {
+                            for batch in &self.instance_culler.batches[i] {
+                                let material = self.material(batch.material);
+                                let Some(pipeline) = self.opaque_pipeline(material) else {
+                                    continue;
+                                };
+                                render_pass.set_pipeline(pipeline);
+                                match material_array {
+                                    Some(_) => render_pass.draw_mesh_lod_from_array(
+                                        mesh,
+                                        batch.lod,
+                                        batch.instances.clone(),
+                                    ),
+                                    None => render_pass.draw_mesh_lod_instanced(
+                                        mesh,
+                                        material,
+                                        batch.lod,
+                                        batch.instances.clone(),
+                                        &self.camera_bind_group,
+                                        &self.light_bind_group,
+                                    ),
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // then blended meshes, back to front
+                if !self.transparency_sorter.batches.is_empty() {
+                    render_pass.set_vertex_buffer(
+                        1, 
+                        self.transparency_sorter.instance_buffer().slice(..)
+                    );
+                    render_pass.set_pipeline(&self.transparent_render_pipeline);
+                    for batch in &self.transparency_sorter.batches {
+                        render_pass.draw_mesh_lod_instanced(
+                            &self.obj_model.meshes[batch.mesh],
+                            self.material(batch.material),
+                            batch.lod,
+                            batch.instances.clone(),
+                            &self.camera_bind_group,
+                            &self.light_bind_group,
+                        );
+                    }
+                }
+            }
+
+            self.debug_draw.draw(&mut render_pass, &self.camera_bind_group);
   
         }
 
+        self.outline.render(
+            &mut encoder,
+            &view,
+            &self.obj_model,
+            &self.camera_bind_group,
+            &self.light_bind_group,
+        );
+
+        self.id_picker.render(
+            &mut encoder,
+            &self.obj_model,
+            &self.instance_buffer,
+            &self.camera_bind_group,
+            &self.light_bind_group,
+        );
+
         // submit will accept anything that implements IntoIter
         self.queue.submit(std::iter::once(encoder.finish()));
+        self.id_picker.map_readback();
         output.present();
 
         Ok(())
     }
 }
 
+// the scene file, or the built in scene when it's missing or broken
+fn load_scene() -> (SceneDescription, SceneGraph) {
+    let loaded = SceneDescription::load(SCENE_FILE)
+        .and_then(|description| Ok((description.build()?, description)));
+    match loaded {
+        Ok((scene, description)) => {
+            info!("loaded scene from {}", SCENE_FILE);
+            (description, scene)
+        }
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("couldn't load scene from {}: {}", SCENE_FILE, e);
+            }
+            let description = SceneDescription::default();
+            let scene = description.build().expect("the built in scene is valid");
+            (description, scene)
+        }
+    }
+}
+
+// where the scene's first light is, the origin without one
+fn scene_light_position(scene: &SceneGraph) -> cgmath::Vector3<f32> {
+    scene.attached(Attachment::Light)
+        .next()
+        .map_or(cgmath::Vector3::zero(), |light| scene.world(light).world_position())
+}
+
+// Locked keeps the cursor still while it's hidden, but isn't supported
+// everywhere, confined at least stops it leaving the window
+fn grab_cursor(window: &Window, grab: bool) {
+    let result = if grab {
+        window.set_cursor_grab(CursorGrabMode::Locked)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+    } else {
+        window.set_cursor_grab(CursorGrabMode::None)
+    };
+    if let Err(e) = result {
+        warn!("couldn't grab the cursor: {}", e);
+    }
+    window.set_cursor_visible(!grab);
+}
+
+// every input State sees goes through here, so it can be recorded
+fn apply_input(state: &mut State, recorder: &mut Option<InputRecorder>, input: InputEvent) {
+    if let Some(recorder) = recorder {
+        recorder.record(&input);
+    }
+    state.input(input);
+}
+
 pub async fn run() {
     tracing_subscriber::fmt::init();
     info!("info!!!");
     warn!("warning");
     error!("eeeeeek");
+
+    // `--record-input <file>` logs the input and frame times that reach the
+    // state, `--replay-input <file>` plays them back in place of the real
+    // input to reproduce a session
+    let mut recorder = None;
+    let mut replay = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--record-input", Some(path)) => match InputRecorder::create(&path) {
+                Ok(input_recorder) => {
+                    info!("recording input to {}", path);
+                    recorder = Some(input_recorder);
+                }
+                Err(e) => error!("couldn't record input to {}: {}", path, e),
+            },
+            ("--replay-input", Some(path)) => match InputReplay::load(&path) {
+                Ok(input_replay) => {
+                    info!("replaying input from {}", path);
+                    replay = Some(input_replay);
+                }
+                Err(e) => error!("couldn't replay input from {}: {}", path, e),
+            },
+            _ => warn!("ignoring argument {}", arg),
+        }
+    }
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new()
         .with_title("Game")
@@ -773,6 +1902,7 @@ pub async fn run() {
 
     let mut state = State::new(&window).await;
     let mut last_render_time = instant::Instant::now();
+    let mut cursor_grabbed = false;
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Wait;
@@ -781,25 +1911,21 @@ pub async fn run() {
             Event::DeviceEvent { 
                 event: DeviceEvent::MouseMotion { delta },
                 .. 
-            } => if state.mouse_pressed {
-                state.camera_controller.process_mouse(delta.0, delta.1)
+            } if replay.is_none() => {
+                apply_input(&mut state, &mut recorder, InputEvent::MouseMotion(delta.0, delta.1));
             }
             
             Event::WindowEvent {
                 ref event,
                 window_id,
-            } if window_id == window.id() && !state.input(event) => {
-                match event {
-                    WindowEvent::CloseRequested
-                    | WindowEvent::KeyboardInput {
-                        input:
-                            KeyboardInput {
-                                state: ElementState::Pressed,
-                                virtual_keycode: Some(VirtualKeyCode::Escape),
-                                ..
-                            },
-                        ..
-                    } => *control_flow = ControlFlow::Exit,
+            } if window_id == window.id() => match InputEvent::from_window_event(event) {
+                // while replaying, only the logged input counts
+                Some(_) if replay.is_some() => {}
+
+                Some(input) => apply_input(&mut state, &mut recorder, input),
+
+                None => match event {
+                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
 
                     WindowEvent::Resized(physical_size) => {
                         state.resize(*physical_size);
@@ -811,13 +1937,37 @@ pub async fn run() {
                     }
 
                     _ => {}
-                }
-            }
+                },
+            },
 
             Event::RedrawRequested(window_id) if window_id == window.id() => {
                 let now = instant::Instant::now();
-                let dt = now - last_render_time;
+                let mut dt = now - last_render_time;
                 last_render_time = now;
+                if let Some(input_replay) = &mut replay {
+                    match input_replay.next_frame() {
+                        Some((inputs, logged_dt)) => {
+                            for input in inputs {
+                                apply_input(&mut state, &mut recorder, input);
+                            }
+                            dt = logged_dt;
+                        }
+                        None => {
+                            info!("input replay finished");
+                            replay = None;
+                        }
+                    }
+                }
+                // while replaying, the logged gamepad input stands in for it
+                #[cfg(feature = "gilrs")]
+                if replay.is_none() {
+                    for input in state.poll_gamepad(dt) {
+                        apply_input(&mut state, &mut recorder, input);
+                    }
+                }
+                if let Some(recorder) = &mut recorder {
+                    recorder.frame(dt);
+                }
                 state.update(dt);
                 match state.render() {
                     Ok(_) => {}
@@ -830,7 +1980,16 @@ pub async fn run() {
                 }
             }
 
+            Event::MainEventsCleared if state.quit_requested => {
+                *control_flow = ControlFlow::Exit;
+            }
+
             Event::MainEventsCleared => {
+                if state.mouse_look != cursor_grabbed {
+                    cursor_grabbed = state.mouse_look;
+                    grab_cursor(&window, cursor_grabbed);
+                }
+
                 // RedrawRequested will only trigger once, unless we manually
                 // request it.
                 window.request_redraw();
@@ -840,3 +1999,84 @@ pub async fn run() {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, Matrix3, Matrix4, Quaternion, Vector3};
+
+    use super::*;
+
+    fn assert_close(actual: impl Into<[[f32; 4]; 4]>, expected: Matrix4<f32>) {
+        let actual: [[f32; 4]; 4] = actual.into();
+        let expected: [[f32; 4]; 4] = expected.into();
+        for (a, e) in actual.iter().flatten().zip(expected.iter().flatten()) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn instances_without_a_usable_material_keep_the_meshes() {
+        let raw = |material| Instance { material, ..Default::default() }.to_raw();
+        assert_eq!(raw(None).material_for(1, 3), 1);
+        assert_eq!(raw(Some(2)).material_for(1, 3), 2);
+        // as gpu_culling.wgsl treats materials that don't exist
+        assert_eq!(raw(Some(3)).material_for(1, 3), 1);
+    }
+
+    #[test]
+    fn children_of_unevenly_scaled_parents_keep_their_shear() {
+        let parent = Instance {
+            position: Vector3::new(1.0, 0.0, 0.0),
+            scale: Vector3::new(2.0, 1.0, 1.0),
+            ..Default::default()
+        };
+        let child = Instance {
+            position: Vector3::new(0.0, 1.0, 0.0),
+            rotation: Quaternion::from_angle_z(Deg(45.0)),
+            ..Default::default()
+        };
+        let world = child.within(&parent);
+        let expected = Matrix4::from_translation(Vector3::new(1.0, 0.0, 0.0))
+            * Matrix4::from_nonuniform_scale(2.0, 1.0, 1.0)
+            * Matrix4::from_translation(Vector3::new(0.0, 1.0, 0.0))
+            * Matrix4::from_angle_z(Deg(45.0));
+        let raw = world.to_raw();
+        assert_close(raw.model, expected);
+        // the child's x and y axes are no longer at right angles
+        let model = Matrix4::from(raw.model);
+        assert!(model.x.truncate().dot(model.y.truncate()).abs() > 0.1);
+
+        let linear = Matrix3::from_cols(
+            expected.x.truncate(),
+            expected.y.truncate(),
+            expected.z.truncate(),
+        );
+        let normal = Matrix3::from(raw.normal);
+        let expected_normal = linear.invert().unwrap().transpose();
+        assert!((normal.x - expected_normal.x).magnitude() < 1e-5);
+        assert!((normal.y - expected_normal.y).magnitude() < 1e-5);
+        assert!((normal.z - expected_normal.z).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn grandchildren_compose_every_ancestor() {
+        let root = Instance {
+            rotation: Quaternion::from_angle_y(Deg(90.0)),
+            scale: Vector3::new(1.0, 3.0, 1.0),
+            ..Default::default()
+        };
+        let middle = Instance {
+            position: Vector3::new(0.0, 0.0, 2.0),
+            rotation: Quaternion::from_angle_x(Deg(30.0)),
+            ..Default::default()
+        };
+        let leaf = Instance {
+            scale: Vector3::new(0.5, 0.5, 0.5),
+            tint: [1.0, 0.0, 0.0],
+            ..Default::default()
+        };
+        let world = leaf.within(&middle.within(&root));
+        assert_close(world.matrix(), root.matrix() * middle.matrix() * leaf.matrix());
+        assert_eq!(world.tint, [1.0, 0.0, 0.0]);
+    }
+}
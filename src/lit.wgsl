@@ -0,0 +1,119 @@
+// The vertex inputs, tangent space and lighting shared by shader.wgsl and
+// material_array.wgsl. Goes in front of either.
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct Light {
+    position: vec3<f32>,
+    color: vec3<f32>,
+}
+@group(2) @binding(0)
+var<uniform> light: Light;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(3) tangent: vec3<f32>,
+    @location(4) bitangent: vec3<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
+    @location(12) tint: vec3<f32>,
+    @location(13) material: u32,
+};
+
+// where a vertex is lit from, all in its tangent space
+struct TangentSpace {
+    clip_position: vec4<f32>,
+    position: vec3<f32>,
+    light_position: vec3<f32>,
+    view_position: vec3<f32>,
+}
+
+// the part of `v` at right angles to the unit vector `n`, normalised
+fn orthogonalise(v: vec3<f32>, n: vec3<f32>) -> vec3<f32> {
+    return normalize(v - dot(v, n) * n);
+}
+
+fn tangent_space(model: VertexInput, instance: InstanceInput) -> TangentSpace {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+
+    // Tangents run along the surface, so they stretch with it like positions
+    // do. Only the normal takes the inverse-transpose. Squaring them back up
+    // keeps the frame orthonormal under uneven scale.
+    let model_linear = mat3x3<f32>(
+        model_matrix[0].xyz,
+        model_matrix[1].xyz,
+        model_matrix[2].xyz,
+    );
+    let world_normal = normalize(normal_matrix * model.normal);
+    let world_tangent = orthogonalise(model_linear * model.tangent, world_normal);
+    let world_bitangent = orthogonalise(
+        orthogonalise(model_linear * model.bitangent, world_normal),
+        world_tangent,
+    );
+    let tangent_matrix = transpose(
+        mat3x3<f32>(
+            world_tangent,
+            world_bitangent,
+            world_normal,
+        )
+    );
+
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+
+    var out: TangentSpace;
+    out.clip_position = camera.view_proj * world_position;
+    out.position = tangent_matrix * world_position.xyz;
+    out.view_position = tangent_matrix * camera.view_pos.xyz;
+    out.light_position = tangent_matrix * light.position;
+    return out;
+}
+
+// the light reaching the camera off a surface, before its colour
+fn lighting(
+    object_normal: vec4<f32>,
+    tangent_position: vec3<f32>,
+    tangent_light_position: vec3<f32>,
+    tangent_view_position: vec3<f32>,
+) -> vec3<f32> {
+    let ambient_strength = 0.1;
+    let ambient_color = light.color * ambient_strength;
+
+    // create the lighting vectors
+    let tangent_normal = object_normal.xyz * 2.0 - 1.0;
+    let light_dir = normalize(tangent_light_position - tangent_position);
+    let view_dir = normalize(tangent_view_position - tangent_position);
+    let half_dir = normalize(view_dir + light_dir);
+
+    let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0);
+    let diffuse_color = light.color * diffuse_strength;
+
+    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), 32.0);
+    let specular_color = specular_strength * light.color;
+
+    return ambient_color + diffuse_color + specular_color;
+}
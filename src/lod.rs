@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Matrix4, MetricSpace, Point3};
+
+use crate::camera::Projection;
+use crate::culling::Aabb;
+use crate::model::{Lod, Mesh, ModelVertex};
+
+// screen size (fraction of the viewport height covered by the bounding
+// sphere) below which each LOD hands over to the next coarser one
+const LOD_SCREEN_SIZES: [f32; 3] = [0.3, 0.15, 0.075];
+// grid resolutions, along the longest axis, for generated LODs
+const CLUSTER_GRID_SIZES: [f32; 3] = [16.0, 8.0, 4.0];
+// a generated LOD has to drop at least this fraction of the triangles
+const MIN_REDUCTION: f32 = 0.25;
+
+pub fn min_screen_size(lod: usize, lod_count: usize) -> f32 {
+    if lod + 1 >= lod_count {
+        0.0
+    } else {
+        let last = LOD_SCREEN_SIZES.len() - 1;
+        LOD_SCREEN_SIZES[lod.min(last)] / 2.0f32.powi(lod.saturating_sub(last) as i32)
+    }
+}
+
+// splits `name_LOD2` into ("name", 2), anything else is LOD 0
+pub fn split_lod_name(name: &str) -> (&str, usize) {
+    name.rsplit_once("_LOD")
+        .and_then(|(base, level)| Some((base, level.parse().ok()?)))
+        .unwrap_or((name, 0))
+}
+
+// Vertex clustering: snaps every vertex to a grid cell, lets the first vertex
+// in each cell stand in for the rest and drops the triangles that collapse.
+// Indices still point into the original vertices.
+fn simplify(vertices: &[ModelVertex], indices: &[u32], bounds: &Aabb, grid_size: f32) -> Vec<u32> {
+    let extents = bounds.max - bounds.min;
+    let cell_size = extents.x.max(extents.y).max(extents.z) / grid_size;
+    if cell_size <= 0.0 {
+        return indices.to_vec();
+    }
+
+    let mut representatives = HashMap::new();
+    let remap = vertices
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let cell = [0, 1, 2].map(|axis| {
+                ((v.position[axis] - bounds.min[axis]) / cell_size).floor() as i32
+            });
+            *representatives.entry(cell).or_insert(i as u32)
+        })
+        .collect::<Vec<_>>();
+
+    indices
+        .chunks(3)
+        .map(|c| [remap[c[0] as usize], remap[c[1] as usize], remap[c[2] as usize]])
+        .filter(|[a, b, c]| a != b && b != c && c != a)
+        .flatten()
+        .collect()
+}
+
+// coarser index lists for a mesh without authored LODs
+pub fn generate_lods(vertices: &[ModelVertex], indices: &[u32], bounds: &Aabb) -> Vec<Vec<u32>> {
+    let mut lods: Vec<Vec<u32>> = Vec::new();
+    for grid_size in CLUSTER_GRID_SIZES {
+        let previous = lods.last().map_or(indices.len(), Vec::len);
+        let simplified = simplify(vertices, indices, bounds, grid_size);
+        if !simplified.is_empty()
+            && (simplified.len() as f32) <= previous as f32 * (1.0 - MIN_REDUCTION)
+        {
+            lods.push(simplified);
+        }
+    }
+    lods
+}
+
+// projected size of the mesh's bounding sphere, 1.0 fills the viewport height
+pub fn screen_size(
+    bounds: &Aabb,
+    model: &Matrix4<f32>,
+    camera_position: Point3<f32>,
+    projection: &Projection,
+) -> f32 {
+    let center = Point3::from_homogeneous(model * bounds.center().to_homogeneous());
+    let scale = model.x.truncate().magnitude()
+        .max(model.y.truncate().magnitude())
+        .max(model.z.truncate().magnitude());
+    let radius = bounds.half_extents().magnitude() * scale;
+    projection.screen_size(radius, camera_position.distance(center))
+}
+
+// Picks a LOD per (mesh, instance) every frame. A LOD is only left once the
+// screen size is past its threshold by the hysteresis fraction, so objects
+// sitting right on a threshold don't flicker between two LODs.
+pub struct LodSelector {
+    pub hysteresis: f32,
+    // indexed by mesh then instance
+    current: Vec<Vec<usize>>,
+}
+
+impl LodSelector {
+    pub fn new(hysteresis: f32) -> Self {
+        Self {
+            hysteresis,
+            current: Vec::new(),
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        meshes: &[Mesh],
+        models: &[Matrix4<f32>],
+        camera_position: Point3<f32>,
+        projection: &Projection,
+    ) {
+        self.current.resize(meshes.len(), Vec::new());
+        for (mesh, current) in meshes.iter().zip(&mut self.current) {
+            current.resize(models.len(), 0);
+            for (model, lod) in models.iter().zip(current.iter_mut()) {
+                let size = screen_size(&mesh.bounds, model, camera_position, projection);
+                *lod = Self::select(&mesh.lods, *lod, size, self.hysteresis);
+            }
+        }
+    }
+
+    fn select(lods: &[Lod], current: usize, size: f32, hysteresis: f32) -> usize {
+        let mut lod = current.min(lods.len().saturating_sub(1));
+        while lod > 0 && size >= lods[lod - 1].min_screen_size * (1.0 + hysteresis) {
+            lod -= 1;
+        }
+        while lod + 1 < lods.len() && size < lods[lod].min_screen_size * (1.0 - hysteresis) {
+            lod += 1;
+        }
+        lod
+    }
+
+    pub fn lod(&self, mesh: usize, instance: usize) -> usize {
+        self.current
+            .get(mesh)
+            .and_then(|lods| lods.get(instance))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // the instances after it move down a place, keeping their LODs
+    pub fn remove_instance(&mut self, instance: usize) {
+        for current in &mut self.current {
+            if instance < current.len() {
+                current.remove(instance);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lods() -> Vec<Lod> {
+        (0..3)
+            .map(|lod| Lod {
+                indices: 0..3,
+                base_vertex: 0,
+                min_screen_size: min_screen_size(lod, 3),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn thresholds_fall_with_each_lod_and_the_last_has_none() {
+        assert_eq!(min_screen_size(0, 3), 0.3);
+        assert_eq!(min_screen_size(1, 3), 0.15);
+        assert_eq!(min_screen_size(2, 3), 0.0);
+        assert_eq!(min_screen_size(0, 1), 0.0);
+        // past the table each halves the last
+        assert_eq!(min_screen_size(4, 6), 0.075 / 4.0);
+    }
+
+    #[test]
+    fn select_needs_to_clear_the_hysteresis_band() {
+        let lods = lods();
+        // at 0.3 with 10% hysteresis LOD 0 holds down to 0.27 and is taken
+        // back from 0.33
+        assert_eq!(LodSelector::select(&lods, 0, 0.28, 0.1), 0);
+        assert_eq!(LodSelector::select(&lods, 0, 0.26, 0.1), 1);
+        assert_eq!(LodSelector::select(&lods, 1, 0.32, 0.1), 1);
+        assert_eq!(LodSelector::select(&lods, 1, 0.34, 0.1), 0);
+    }
+
+    #[test]
+    fn select_jumps_several_lods_at_once() {
+        let lods = lods();
+        assert_eq!(LodSelector::select(&lods, 0, 0.01, 0.1), 2);
+        assert_eq!(LodSelector::select(&lods, 2, 1.0, 0.1), 0);
+        // a stale current LOD from a mesh with more of them is clamped
+        assert_eq!(LodSelector::select(&lods, 7, 0.01, 0.1), 2);
+        assert_eq!(LodSelector::select(&lods[..1], 0, 0.0, 0.1), 0);
+    }
+
+    #[test]
+    fn removed_instances_take_their_lods_with_them() {
+        let mut selector = LodSelector::new(0.1);
+        selector.current = vec![vec![0, 1, 2], vec![2, 0, 1]];
+        selector.remove_instance(1);
+        assert_eq!(selector.current, [[0, 2], [2, 1]]);
+        // instances never selected for have nothing to remove
+        selector.remove_instance(5);
+        assert_eq!((selector.lod(0, 1), selector.lod(1, 1)), (2, 1));
+        assert_eq!(selector.lod(0, 2), 0);
+    }
+
+    #[test]
+    fn lod_suffixes_are_split_off() {
+        assert_eq!(split_lod_name("tree_LOD2"), ("tree", 2));
+        assert_eq!(split_lod_name("tree"), ("tree", 0));
+        assert_eq!(split_lod_name("tree_LODx"), ("tree_LODx", 0));
+    }
+}
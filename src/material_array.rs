@@ -0,0 +1,496 @@
+use std::mem;
+use std::num::{NonZeroU32, NonZeroU64};
+use std::ops::Range;
+
+use wgpu::util::DeviceExt;
+
+use crate::model::{self, AlphaMode, Vertex};
+use crate::{texture, InstanceRaw, PipelineOptions, NO_MATERIAL};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialParams {
+    opacity: f32,
+    alpha_cutoff: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ArrayParams {
+    // drawn in place of every instance's material, NO_MATERIAL for none
+    override_material: u32,
+    // uniforms require 16 byte spacing
+    _padding: [u32; 3],
+}
+
+impl ArrayParams {
+    fn new(override_material: Option<usize>) -> Self {
+        Self {
+            override_material: override_material.map_or(NO_MATERIAL, |material| material as u32),
+            _padding: [0; 3],
+        }
+    }
+}
+
+// how the material textures reach the shader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextureLayout {
+    // stretched into the layers of a texture array for each kind of map
+    Layers,
+    // each material's own textures in binding arrays
+    Bindings,
+}
+
+// Every material of a model in one bind group, bound once for all the
+// meshes rather than each binding their own. Material parameters go in a
+// storage buffer indexed by material. Each instance says which material it's
+// drawn with, filled in by culling, so a single draw can mix materials. The
+// debug material goes after the model's and can stand in for all of them.
+pub struct MaterialArray {
+    render_pipeline: wgpu::RenderPipeline,
+    cutout_render_pipeline: wgpu::RenderPipeline,
+    bind_group: wgpu::BindGroup,
+    params_buffer: wgpu::Buffer,
+    // index of the debug material
+    debug_material: usize,
+    texture_layout: TextureLayout,
+}
+
+impl MaterialArray {
+    // binding arrays are used when these are available
+    pub const FEATURES: wgpu::Features = wgpu::Features::TEXTURE_BINDING_ARRAY
+        .union(wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING);
+
+    // WebGL can't read storage buffers
+    pub fn is_supported(device: &wgpu::Device) -> bool {
+        device.limits().max_storage_buffers_per_shader_stage > 0
+    }
+
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color_format: wgpu::TextureFormat,
+        camera_layout: &wgpu::BindGroupLayout,
+        light_layout: &wgpu::BindGroupLayout,
+        model: &model::Model,
+        debug_material: &model::Material,
+    ) -> Self {
+        let materials = model.materials.iter()
+            .chain(std::iter::once(debug_material))
+            .collect::<Vec<_>>();
+        let material_count = materials.len() as u32;
+        // a binding array takes up a binding per texture
+        let texture_layout = if device.features().contains(Self::FEATURES)
+            && material_count * 2 <= device.limits().max_sampled_textures_per_shader_stage
+        {
+            TextureLayout::Bindings
+        } else {
+            TextureLayout::Layers
+        };
+
+        let (view_dimension, count) = match texture_layout {
+            TextureLayout::Layers => (wgpu::TextureViewDimension::D2Array, None),
+            TextureLayout::Bindings => {
+                (wgpu::TextureViewDimension::D2, NonZeroU32::new(material_count))
+            }
+        };
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count,
+        };
+        let bind_group_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    texture_entry(0),
+                    texture_entry(1),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::VERTEX,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: NonZeroU64::new(
+                                mem::size_of::<ArrayParams>() as u64
+                            ),
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("material_array_bind_group_layout"),
+            }
+        );
+
+        let material_params = materials.iter()
+            .map(|material| MaterialParams {
+                opacity: material.opacity,
+                alpha_cutoff: match material.alpha_mode {
+                    AlphaMode::Mask(cutoff) => cutoff,
+                    _ => 0.0,
+                },
+            })
+            .collect::<Vec<_>>();
+        let material_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Material Array Params Buffer"),
+                contents: bytemuck::cast_slice(&material_params),
+                usage: wgpu::BufferUsages::STORAGE,
+            }
+        );
+
+        let params_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Material Array Override Buffer"),
+                contents: bytemuck::bytes_of(&ArrayParams::new(None)),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        // only one pair is filled in, for the bindings to borrow
+        let (diffuse_view, normal_view);
+        let (diffuse_views, normal_views);
+        let (diffuse, normal) = match texture_layout {
+            TextureLayout::Layers => {
+                // the formats Texture::from_image gives them
+                diffuse_view = pack_layers(
+                    device,
+                    queue,
+                    &materials.iter()
+                        .map(|material| &material.diffuse_texture)
+                        .collect::<Vec<_>>(),
+                    wgpu::TextureFormat::Rgba8UnormSrgb,
+                );
+                normal_view = pack_layers(
+                    device,
+                    queue,
+                    &materials.iter()
+                        .map(|material| &material.normal_texture)
+                        .collect::<Vec<_>>(),
+                    wgpu::TextureFormat::Rgba8Unorm,
+                );
+                (
+                    wgpu::BindingResource::TextureView(&diffuse_view),
+                    wgpu::BindingResource::TextureView(&normal_view),
+                )
+            }
+            TextureLayout::Bindings => {
+                diffuse_views = materials.iter()
+                    .map(|material| &material.diffuse_texture.view)
+                    .collect::<Vec<_>>();
+                normal_views = materials.iter()
+                    .map(|material| &material.normal_texture.view)
+                    .collect::<Vec<_>>();
+                (
+                    wgpu::BindingResource::TextureViewArray(&diffuse_views),
+                    wgpu::BindingResource::TextureViewArray(&normal_views),
+                )
+            }
+        };
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: diffuse,
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: normal,
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: material_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: params_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("material_array_bind_group"),
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Material Array Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout, camera_layout, light_layout],
+            push_constant_ranges: &[],
+        });
+        let source = format!(
+            "{}\n{}\n{}",
+            include_str!("lit.wgsl"),
+            match texture_layout {
+                TextureLayout::Layers => include_str!("material_layers.wgsl"),
+                TextureLayout::Bindings => include_str!("material_bindings.wgsl"),
+            },
+            include_str!("material_array.wgsl"),
+        );
+        let shader = |label| wgpu::ShaderModuleDescriptor {
+            label: Some(label),
+            source: wgpu::ShaderSource::Wgsl(source.as_str().into()),
+        };
+        let render_pipeline = crate::create_render_pipeline(
+            device,
+            shader("Material Array Shader"),
+            &layout,
+            &[model::ModelVertex::desc(), InstanceRaw::desc()],
+            color_format,
+            Some(texture::Texture::DEPTH_FORMAT),
+        );
+        let cutout_render_pipeline = crate::create_render_pipeline_with_options(
+            device,
+            shader("Material Array Cutout Shader"),
+            &layout,
+            &[model::ModelVertex::desc(), InstanceRaw::desc()],
+            color_format,
+            Some(texture::Texture::DEPTH_FORMAT),
+            PipelineOptions {
+                fs_entry_point: "fs_cutout",
+                ..Default::default()
+            },
+        );
+
+        Self {
+            render_pipeline,
+            cutout_render_pipeline,
+            bind_group,
+            params_buffer,
+            debug_material: model.materials.len(),
+            texture_layout,
+        }
+    }
+
+    // whether the debug material is drawn in place of every other, takes
+    // effect from the next submit
+    pub fn use_debug_material(&self, queue: &wgpu::Queue, enabled: bool) {
+        let params = ArrayParams::new(enabled.then_some(self.debug_material));
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::bytes_of(&params));
+    }
+
+    pub fn texture_layout(&self) -> TextureLayout {
+        self.texture_layout
+    }
+
+    // blended meshes are sorted and drawn with their own materials instead
+    pub fn pipeline(&self, alpha_mode: AlphaMode) -> &wgpu::RenderPipeline {
+        match alpha_mode {
+            AlphaMode::Mask(_) => &self.cutout_render_pipeline,
+            _ => &self.render_pipeline,
+        }
+    }
+}
+
+// Stretches the textures into the layers of a new texture array the size of
+// the largest of them, returning a view of all its layers.
+fn pack_layers(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    textures: &[&texture::Texture],
+    format: wgpu::TextureFormat,
+) -> wgpu::TextureView {
+    let size = wgpu::Extent3d {
+        width: textures.iter().map(|texture| texture.size.width).max().unwrap_or(1),
+        height: textures.iter().map(|texture| texture.size.height).max().unwrap_or(1),
+        depth_or_array_layers: textures.len() as u32,
+    };
+    let array = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Material Texture Array"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+    });
+
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ],
+        label: Some("blit_bind_group_layout"),
+    });
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Blit Pipeline Layout"),
+        bind_group_layouts: &[&bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    let pipeline = crate::create_render_pipeline(
+        device,
+        wgpu::ShaderModuleDescriptor {
+            label: Some("Blit Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("blit.wgsl").into()),
+        },
+        &layout,
+        &[],
+        format,
+        None,
+    );
+    // smooth when stretching, unlike the textures' own samplers
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Material Array Encoder"),
+    });
+    for (layer, texture) in textures.iter().enumerate() {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("blit_bind_group"),
+        });
+        let layer_view = array.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            base_array_layer: layer as u32,
+            array_layer_count: NonZeroU32::new(1),
+            ..Default::default()
+        });
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Blit Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &layer_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(&pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+    queue.submit(std::iter::once(encoder.finish()));
+
+    array.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    })
+}
+
+// Draws like DrawModel, but with the materials from the array, bound once
+// for all the draws after it.
+pub trait DrawMaterialArray<'a> {
+    fn set_material_array(
+        &mut self,
+        material_array: &'a MaterialArray,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    );
+    fn draw_mesh_lod_from_array(&mut self, mesh: &'a model::Mesh, lod: usize, instances: Range<u32>);
+    // instance count comes from the indirect buffer, see wgpu::util::DrawIndexedIndirect
+    fn draw_mesh_indirect_from_array(
+        &mut self,
+        mesh: &'a model::Mesh,
+        indirect_buffer: &'a wgpu::Buffer,
+        indirect_offset: wgpu::BufferAddress,
+    );
+}
+
+impl<'a, 'b> DrawMaterialArray<'b> for wgpu::RenderPass<'a>
+    where 'b: 'a
+{
+    fn set_material_array(
+        &mut self,
+        material_array: &'b MaterialArray,
+        camera_bind_group: &'b wgpu::BindGroup,
+        light_bind_group: &'b wgpu::BindGroup,
+    ) {
+        self.set_bind_group(0, &material_array.bind_group, &[]);
+        self.set_bind_group(1, camera_bind_group, &[]);
+        self.set_bind_group(2, light_bind_group, &[]);
+    }
+
+    fn draw_mesh_lod_from_array(&mut self, mesh: &'b model::Mesh, lod: usize, instances: Range<u32>) {
+        let lod = &mesh.lods[lod];
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.draw_indexed(lod.indices.clone(), lod.base_vertex, instances);
+    }
+
+    fn draw_mesh_indirect_from_array(
+        &mut self,
+        mesh: &'b model::Mesh,
+        indirect_buffer: &'b wgpu::Buffer,
+        indirect_offset: wgpu::BufferAddress,
+    ) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.draw_indexed_indirect(indirect_buffer, indirect_offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_are_packed_as_the_shader_reads_them() {
+        // a u32 rounded up to 16 bytes
+        assert_eq!(mem::size_of::<ArrayParams>(), 16);
+        assert_eq!(ArrayParams::new(None).override_material, NO_MATERIAL);
+        assert_eq!(ArrayParams::new(Some(3)).override_material, 3);
+        let source = include_str!("material_array.wgsl");
+        assert!(source.contains(&format!("let NO_MATERIAL: u32 = {:#x}u;", NO_MATERIAL)));
+    }
+}
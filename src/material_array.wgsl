@@ -0,0 +1,89 @@
+// The lit shader with every material in one bind group. lit.wgsl goes in
+// front of this, then whichever of material_layers.wgsl or
+// material_bindings.wgsl declares the textures, along with sample_diffuse
+// and sample_normal.
+
+// Vertex shader
+
+struct Material {
+    opacity: f32,
+    alpha_cutoff: f32,
+}
+@group(0) @binding(3)
+var<storage, read> materials: array<Material>;
+
+// set for the whole pass
+struct ArrayParams {
+    // drawn in place of every instance's material unless NO_MATERIAL
+    override_material: u32,
+}
+@group(0) @binding(4)
+var<uniform> params: ArrayParams;
+
+let NO_MATERIAL: u32 = 0xffffffffu;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) tangent_position: vec3<f32>,
+    @location(2) tangent_light_position: vec3<f32>,
+    @location(3) tangent_view_position: vec3<f32>,
+    @location(4) tint: vec3<f32>,
+    @location(5) @interpolate(flat) material: u32,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let tangent = tangent_space(model, instance);
+
+    var out: VertexOutput;
+    out.clip_position = tangent.clip_position;
+    out.tex_coords = model.tex_coords;
+    out.tangent_position = tangent.position;
+    out.tangent_view_position = tangent.view_position;
+    out.tangent_light_position = tangent.light_position;
+    out.tint = instance.tint;
+    // culling filled in the material each instance is drawn with
+    out.material = instance.material;
+    if (params.override_material != NO_MATERIAL) {
+        out.material = params.override_material;
+    }
+
+    return out;
+}
+
+
+// Fragment shader
+
+fn shade(in: VertexOutput, object_colour: vec4<f32>) -> vec4<f32> {
+    let object_normal: vec4<f32> = sample_normal(in.material, in.tex_coords);
+    let light_color = lighting(
+        object_normal,
+        in.tangent_position,
+        in.tangent_light_position,
+        in.tangent_view_position,
+    );
+    let result = light_color * object_colour.xyz * in.tint;
+
+    return vec4(result, object_colour.a * materials[in.material].opacity);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let object_colour: vec4<f32> = sample_diffuse(in.material, in.tex_coords);
+    return shade(in, object_colour);
+}
+
+// alpha tested variant for cutout materials
+@fragment
+fn fs_cutout(in: VertexOutput) -> @location(0) vec4<f32> {
+    let object_colour: vec4<f32> = sample_diffuse(in.material, in.tex_coords);
+    let material = materials[in.material];
+    if (object_colour.a * material.opacity < material.alpha_cutoff) {
+        discard;
+    }
+    return shade(in, object_colour);
+}
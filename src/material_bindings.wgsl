@@ -0,0 +1,18 @@
+// Every material's own textures in binding arrays, as they are. Needs
+// non-uniform indexing, as instances in one draw can pick different
+// materials. Goes in front of material_array.wgsl.
+
+@group(0) @binding(0)
+var t_diffuse: binding_array<texture_2d<f32>>;
+@group(0) @binding(1)
+var t_normal: binding_array<texture_2d<f32>>;
+@group(0) @binding(2)
+var s_material: sampler;
+
+fn sample_diffuse(material: u32, tex_coords: vec2<f32>) -> vec4<f32> {
+    return textureSample(t_diffuse[material], s_material, tex_coords);
+}
+
+fn sample_normal(material: u32, tex_coords: vec2<f32>) -> vec4<f32> {
+    return textureSample(t_normal[material], s_material, tex_coords);
+}
@@ -0,0 +1,17 @@
+// Every material's textures as a layer of a texture array, stretched to the
+// size of the largest. Goes in front of material_array.wgsl.
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d_array<f32>;
+@group(0) @binding(1)
+var t_normal: texture_2d_array<f32>;
+@group(0) @binding(2)
+var s_material: sampler;
+
+fn sample_diffuse(material: u32, tex_coords: vec2<f32>) -> vec4<f32> {
+    return textureSample(t_diffuse, s_material, tex_coords, i32(material));
+}
+
+fn sample_normal(material: u32, tex_coords: vec2<f32>) -> vec4<f32> {
+    return textureSample(t_normal, s_material, tex_coords, i32(material));
+}
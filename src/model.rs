@@ -1,6 +1,8 @@
 use std::ops::Range;
 use wgpu::VertexAttribute;
+use wgpu::util::DeviceExt;
 use crate::texture;
+use crate::culling::Aabb;
 
 pub trait Vertex {
     const ATTRIBS: [VertexAttribute; 2] = wgpu::vertex_attr_array![
@@ -58,10 +60,75 @@ impl Vertex for ModelVertex {
     }
 }
 
+// how a material's alpha (texture alpha times opacity) is treated
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum AlphaMode {
+    // alpha is ignored, drawn with depth writes in the opaque pass
+    Opaque,
+    // fragments below the cutoff are discarded, otherwise treated as opaque
+    Mask(f32),
+    // alpha blended, drawn back to front after everything opaque
+    Blend,
+}
+
+impl AlphaMode {
+    // An explicit `alpha_mode opaque|mask [cutoff]|blend` line in the MTL
+    // wins, otherwise a dissolve below 1 means blend and a dissolve map
+    // means cutout. The map itself isn't sampled, cutouts test the diffuse
+    // texture's alpha, so without one the map is ignored.
+    pub fn from_mtl(material: &tobj::Material, diffuse_has_alpha: bool) -> Self {
+        if let Some(mode) = material.unknown_param.get("alpha_mode") {
+            let mut params = mode.split_whitespace();
+            match params.next() {
+                Some("opaque") => return Self::Opaque,
+                Some("mask") => {
+                    let cutoff = params.next()
+                        .and_then(|c| c.parse().ok())
+                        .unwrap_or(0.5);
+                    return Self::Mask(cutoff);
+                }
+                Some("blend") => return Self::Blend,
+                _ => tracing::warn!(
+                    "unknown alpha_mode {:?} in material {:?}", mode, material.name
+                ),
+            }
+        }
+
+        if material.dissolve < 1.0 {
+            Self::Blend
+        } else if !material.dissolve_texture.is_empty() {
+            if diffuse_has_alpha {
+                Self::Mask(0.5)
+            } else {
+                tracing::warn!(
+                    "material {:?} has a dissolve map but its diffuse texture has no alpha, \
+                    drawing it opaque",
+                    material.name,
+                );
+                Self::Opaque
+            }
+        } else {
+            Self::Opaque
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialUniform {
+    opacity: f32,
+    alpha_cutoff: f32,
+    // uniforms require 16 byte spacing
+    _padding: [f32; 2],
+}
+
 pub struct Material {
     pub name: String,
     pub diffuse_texture: texture::Texture,
     pub normal_texture: texture::Texture,
+    pub alpha_mode: AlphaMode,
+    pub opacity: f32,
+    pub uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
 }
 
@@ -71,8 +138,26 @@ impl Material {
         name: &str,
         diffuse_texture: texture::Texture,
         normal_texture: texture::Texture,
+        alpha_mode: AlphaMode,
+        opacity: f32,
         layout: &wgpu::BindGroupLayout,
     ) -> Self {
+        let uniform = MaterialUniform {
+            opacity,
+            alpha_cutoff: match alpha_mode {
+                AlphaMode::Mask(cutoff) => cutoff,
+                _ => 0.0,
+            },
+            _padding: [0.0; 2],
+        };
+        let uniform_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} Uniform Buffer", name)),
+                contents: bytemuck::cast_slice(&[uniform]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            }
+        );
+
         let bind_group = device.create_bind_group(
             &wgpu::BindGroupDescriptor {
                 label: Some(name),
@@ -94,6 +179,10 @@ impl Material {
                         binding: 3,
                         resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
                     },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: uniform_buffer.as_entire_binding(),
+                    },
                 ],
             }
         );
@@ -102,16 +191,39 @@ impl Material {
             name: String::from(name), 
             diffuse_texture, 
             normal_texture, 
+            alpha_mode,
+            opacity,
+            uniform_buffer,
             bind_group,
         }
     }
 }
 
+// a range of the mesh's index buffer drawn at one level of detail
+pub struct Lod {
+    pub indices: Range<u32>,
+    pub base_vertex: i32,
+    // used while the mesh covers at least this fraction of the screen height
+    pub min_screen_size: f32,
+}
+
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
+    // index count of the full detail mesh, which always comes first
     pub num_elements: u32,
+    // full detail first, each coarser than the last
+    pub lods: Vec<Lod>,
+    // line list of the unique triangle edges, used to draw wireframes on
+    // adapters without PolygonMode::Line
+    pub edge_index_buffer: wgpu::Buffer,
+    pub num_edge_elements: u32,
+    // model space bounds, for culling
+    pub bounds: Aabb,
+    // full detail triangles kept on the CPU for ray picking
+    pub positions: Vec<cgmath::Point3<f32>>,
+    pub indices: Vec<u32>,
     pub material: usize,
 }
 
@@ -136,6 +248,25 @@ pub trait DrawModel<'a> {
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     );
+    fn draw_mesh_lod_instanced(
+        &mut self, 
+        mesh: &'a Mesh, 
+        material: &'a Material,
+        lod: usize,
+        instances: Range<u32>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    );
+    // instance count comes from the indirect buffer, see wgpu::util::DrawIndexedIndirect
+    fn draw_mesh_indirect(
+        &mut self,
+        mesh: &'a Mesh,
+        material: &'a Material,
+        indirect_buffer: &'a wgpu::Buffer,
+        indirect_offset: wgpu::BufferAddress,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    );
     fn draw_model(
         &mut self,
         model: &'a Model,
@@ -180,6 +311,19 @@ impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'b wgpu::BindGroup,
     ) {
+        self.draw_mesh_lod_instanced(mesh, material, 0, instances, camera_bind_group, light_bind_group);
+    }
+
+    fn draw_mesh_lod_instanced(
+        &mut self, 
+        mesh: &'b Mesh, 
+        material: &'b Material,
+        lod: usize,
+        instances: Range<u32>,
+        camera_bind_group: &'b wgpu::BindGroup,
+        light_bind_group: &'b wgpu::BindGroup,
+    ) {
+        let lod = &mesh.lods[lod];
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
         self.set_index_buffer(
             mesh.index_buffer.slice(..), 
@@ -188,7 +332,27 @@ impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, camera_bind_group, &[]);
         self.set_bind_group(2, light_bind_group, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        self.draw_indexed(lod.indices.clone(), lod.base_vertex, instances);
+    }
+
+    fn draw_mesh_indirect(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        indirect_buffer: &'b wgpu::Buffer,
+        indirect_offset: wgpu::BufferAddress,
+        camera_bind_group: &'b wgpu::BindGroup,
+        light_bind_group: &'b wgpu::BindGroup,
+    ) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(
+            mesh.index_buffer.slice(..), 
+            wgpu::IndexFormat::Uint32
+        );
+        self.set_bind_group(0, &material.bind_group, &[]);
+        self.set_bind_group(1, camera_bind_group, &[]);
+        self.set_bind_group(2, light_bind_group, &[]);
+        self.draw_indexed_indirect(indirect_buffer, indirect_offset);
     }
 
     fn draw_model(
@@ -336,3 +500,70 @@ impl<'a, 'b> DrawLight<'b> for wgpu::RenderPass<'a>
 
 
 
+
+pub trait DrawEdges<'a> {
+    fn draw_mesh_edges_instanced(
+        &mut self,
+        mesh: &'a Mesh,
+        instances: Range<u32>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    );
+}
+
+impl<'a, 'b> DrawEdges<'b> for wgpu::RenderPass<'a>
+    where 'b: 'a
+{
+    // the edge shader doesn't sample anything, but the pipeline layout
+    // still expects a material to be bound in group 0
+    fn draw_mesh_edges_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        instances: Range<u32>,
+        camera_bind_group: &'b wgpu::BindGroup,
+        light_bind_group: &'b wgpu::BindGroup,
+    ) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(
+            mesh.edge_index_buffer.slice(..),
+            wgpu::IndexFormat::Uint32
+        );
+        self.set_bind_group(1, camera_bind_group, &[]);
+        self.set_bind_group(2, light_bind_group, &[]);
+        self.draw_indexed(0..mesh.num_edge_elements, 0, instances);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mtl(dissolve: f32, dissolve_texture: &str, alpha_mode: Option<&str>) -> tobj::Material {
+        let mut material = tobj::Material {
+            dissolve,
+            dissolve_texture: dissolve_texture.to_string(),
+            ..Default::default()
+        };
+        if let Some(alpha_mode) = alpha_mode {
+            material.unknown_param.insert("alpha_mode".to_string(), alpha_mode.to_string());
+        }
+        material
+    }
+
+    #[test]
+    fn dissolve_maps_cut_out_only_through_diffuse_alpha() {
+        assert_eq!(AlphaMode::from_mtl(&mtl(1.0, "mask.png", None), true), AlphaMode::Mask(0.5));
+        // nothing for the cutout to test, the mask would be ignored anyway
+        assert_eq!(AlphaMode::from_mtl(&mtl(1.0, "mask.png", None), false), AlphaMode::Opaque);
+    }
+
+    #[test]
+    fn dissolve_and_explicit_modes() {
+        assert_eq!(AlphaMode::from_mtl(&mtl(1.0, "", None), true), AlphaMode::Opaque);
+        assert_eq!(AlphaMode::from_mtl(&mtl(0.5, "", None), false), AlphaMode::Blend);
+        let explicit = mtl(0.5, "mask.png", Some("mask 0.25"));
+        assert_eq!(AlphaMode::from_mtl(&explicit, false), AlphaMode::Mask(0.25));
+        let explicit = mtl(0.5, "", Some("opaque"));
+        assert_eq!(AlphaMode::from_mtl(&explicit, true), AlphaMode::Opaque);
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::BTreeSet;
+
+use wgpu::util::DeviceExt;
+
+use crate::model::{self, DrawModel, Vertex};
+use crate::{Instance, InstanceRaw, PipelineOptions};
+
+const STENCIL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth24PlusStencil8;
+const SELECTED: u32 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct OutlineUniform {
+    color: [f32; 4],
+    viewport_size: [f32; 2],
+    thickness: f32,
+    // uniforms require 16 byte spacing
+    _padding: f32,
+}
+
+impl OutlineUniform {
+    fn new(color: [f32; 4], config: &wgpu::SurfaceConfiguration, thickness: f32) -> Self {
+        Self {
+            color,
+            viewport_size: [config.width as f32, config.height as f32],
+            thickness,
+            _padding: 0.0,
+        }
+    }
+}
+
+// the selected instances in index order, skipping any that no longer exist
+fn selected_instances(instances: &[Instance], selection: &BTreeSet<usize>) -> Vec<InstanceRaw> {
+    selection.iter()
+        .filter_map(|&i| instances.get(i))
+        .map(Instance::to_raw)
+        .collect()
+}
+
+// Outlines the selected instances. They are drawn once to mark their
+// silhouette in a stencil buffer, then again pushed out along their normals,
+// coloured only where the stencil isn't marked. Neither pass is depth
+// tested, so the outline shows through whatever is in front of the
+// selection.
+pub struct OutlineRenderer {
+    pub color: [f32; 4],
+    // in pixels
+    pub thickness: f32,
+    mask_pipeline: wgpu::RenderPipeline,
+    outline_pipeline: wgpu::RenderPipeline,
+    uniform_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    // the main depth buffer has no stencil, this one has no depth worth
+    // keeping
+    stencil_view: wgpu::TextureView,
+    instance_buffer: wgpu::Buffer,
+    capacity: usize,
+    num_instances: u32,
+}
+
+impl OutlineRenderer {
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        bind_group_layouts: [&wgpu::BindGroupLayout; 3],
+    ) -> Self {
+        let color = [1.0, 0.6, 0.0, 1.0];
+        let thickness = 3.0;
+
+        let uniform_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Outline Uniform Buffer"),
+                contents: bytemuck::cast_slice(&[OutlineUniform::new(color, config, thickness)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let bind_group_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+                label: Some("outline_bind_group_layout"),
+            }
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+            label: Some("outline_bind_group"),
+        });
+
+        // laid out like the main pipeline so DrawModel's bind groups still
+        // line up, the outline settings go after them
+        let [texture_layout, camera_layout, light_layout] = bind_group_layouts;
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Outline Pipeline Layout"),
+            bind_group_layouts: &[texture_layout, camera_layout, light_layout, &bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let create_pipeline = |options: PipelineOptions| {
+            let shader = wgpu::ShaderModuleDescriptor {
+                label: Some("Outline Shader"),
+                source: wgpu::ShaderSource::Wgsl(include_str!("outline.wgsl").into()),
+            };
+            crate::create_render_pipeline_with_options(
+                device,
+                shader,
+                &layout,
+                &[model::ModelVertex::desc(), InstanceRaw::desc()],
+                config.format,
+                Some(STENCIL_FORMAT),
+                PipelineOptions {
+                    depth_write_enabled: false,
+                    depth_compare: wgpu::CompareFunction::Always,
+                    ..options
+                },
+            )
+        };
+        let stencil = |compare, pass_op, write_mask| {
+            let face = wgpu::StencilFaceState {
+                compare,
+                fail_op: wgpu::StencilOperation::Keep,
+                depth_fail_op: wgpu::StencilOperation::Keep,
+                pass_op,
+            };
+            wgpu::StencilState {
+                front: face,
+                back: face,
+                read_mask: 0xff,
+                write_mask,
+            }
+        };
+        let mask_pipeline = create_pipeline(PipelineOptions {
+            vs_entry_point: "vs_mask",
+            fs_entry_point: "fs_mask",
+            write_mask: wgpu::ColorWrites::empty(),
+            stencil: stencil(
+                wgpu::CompareFunction::Always,
+                wgpu::StencilOperation::Replace,
+                0xff,
+            ),
+            ..Default::default()
+        });
+        let outline_pipeline = create_pipeline(PipelineOptions {
+            stencil: stencil(
+                wgpu::CompareFunction::NotEqual,
+                wgpu::StencilOperation::Keep,
+                0,
+            ),
+            ..Default::default()
+        });
+
+        Self {
+            color,
+            thickness,
+            mask_pipeline,
+            outline_pipeline,
+            uniform_buffer,
+            bind_group,
+            stencil_view: Self::create_stencil_view(device, config),
+            instance_buffer: Self::create_instance_buffer(device, 1),
+            capacity: 1,
+            num_instances: 0,
+        }
+    }
+
+    fn create_stencil_view(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> wgpu::TextureView {
+        device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("Outline Stencil Texture"),
+                size: wgpu::Extent3d {
+                    width: config.width,
+                    height: config.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: STENCIL_FORMAT,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            })
+            .create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Outline Instance Buffer"),
+            size: (capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        self.stencil_view = Self::create_stencil_view(device, config);
+    }
+
+    // uploads the selected instances and the current settings
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        config: &wgpu::SurfaceConfiguration,
+        instances: &[Instance],
+        selection: &BTreeSet<usize>,
+    ) {
+        let instance_data = selected_instances(instances, selection);
+        self.num_instances = instance_data.len() as u32;
+        if instance_data.is_empty() {
+            return;
+        }
+        if instance_data.len() > self.capacity {
+            self.capacity = instance_data.len().next_power_of_two();
+            self.instance_buffer = Self::create_instance_buffer(device, self.capacity);
+        }
+        queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+
+        let uniform = OutlineUniform::new(self.color, config, self.thickness);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    // draws over what's already in `view`
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        model: &model::Model,
+        camera_bind_group: &wgpu::BindGroup,
+        light_bind_group: &wgpu::BindGroup,
+    ) {
+        if self.num_instances == 0 {
+            return;
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Outline Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.stencil_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: false,
+                }),
+                stencil_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(0),
+                    store: false,
+                }),
+            }),
+        });
+        render_pass.set_stencil_reference(SELECTED);
+        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
+        render_pass.set_bind_group(3, &self.bind_group, &[]);
+
+        // every mask has to be in place before any outline is drawn
+        for pipeline in [&self.mask_pipeline, &self.outline_pipeline] {
+            render_pass.set_pipeline(pipeline);
+            for mesh in &model.meshes {
+                render_pass.draw_mesh_instanced(
+                    mesh,
+                    &model.materials[mesh.material],
+                    0..self.num_instances,
+                    camera_bind_group,
+                    light_bind_group,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+
+    use super::*;
+
+    fn at(x: f32) -> Instance {
+        Instance {
+            position: Vector3::new(x, 0.0, 0.0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_existing_selected_instances_are_drawn() {
+        let instances = [at(0.0), at(1.0), at(2.0), at(3.0)];
+        let selection = BTreeSet::from([3, 1, 7]);
+        let raws = selected_instances(&instances, &selection);
+        assert_eq!(raws.iter().map(|raw| raw.model[3][0]).collect::<Vec<_>>(), [1.0, 3.0]);
+        assert!(selected_instances(&instances, &BTreeSet::new()).is_empty());
+    }
+
+    #[test]
+    fn uniform_matches_the_shader_layout() {
+        // a vec4, then a vec2 and a float rounded up to 16 bytes
+        assert_eq!(std::mem::size_of::<OutlineUniform>(), 32);
+    }
+}
@@ -0,0 +1,96 @@
+// Vertex shader
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct OutlineUniform {
+    color: vec4<f32>,
+    // in pixels
+    viewport_size: vec2<f32>,
+    thickness: f32,
+}
+@group(3) @binding(0)
+var<uniform> outline: OutlineUniform;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(2) normal: vec3<f32>,
+}
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+};
+
+fn model_matrix(instance: InstanceInput) -> mat4x4<f32> {
+    return mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+}
+
+// the selected silhouette, marked in the stencil buffer
+@vertex
+fn vs_mask(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * model_matrix(instance) * vec4<f32>(model.position, 1.0);
+    return out;
+}
+
+// the same geometry pushed out along its normals by a fixed number of
+// pixels, whatever is left outside the mask is the outline
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+    let world_normal = normalize(normal_matrix * model.normal);
+    var clip_position = camera.view_proj * model_matrix(instance) * vec4<f32>(model.position, 1.0);
+    let clip_normal = (camera.view_proj * vec4<f32>(world_normal, 0.0)).xy;
+    if (length(clip_normal) > 0.0) {
+        // clip space spans 2 units across the viewport, scaled by w so the
+        // offset survives the perspective divide
+        let offset = normalize(clip_normal) * outline.thickness * 2.0 / outline.viewport_size;
+        clip_position = vec4<f32>(clip_position.xy + offset * clip_position.w, clip_position.zw);
+    }
+
+    var out: VertexOutput;
+    out.clip_position = clip_position;
+    return out;
+}
+
+// Fragment shader
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return outline.color;
+}
+
+// only the stencil is written
+@fragment
+fn fs_mask(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(0.0);
+}
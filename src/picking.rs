@@ -0,0 +1,282 @@
+use cgmath::*;
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+
+use crate::camera::Projection;
+use crate::culling::Aabb;
+use crate::model::Mesh;
+use crate::{texture, Instance};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    // normalised for world space rays, so distances along them are in world
+    // units
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    // starts on the near plane under a pixel and heads away from the camera,
+    // works for any projection kind
+    pub fn from_screen(
+        position: PhysicalPosition<f64>,
+        size: PhysicalSize<u32>,
+        view: Matrix4<f32>,
+        projection: &Projection,
+    ) -> Option<Self> {
+        let inverse = (projection.calc_matrix() * view).invert()?;
+        let x = (2.0 * position.x / size.width as f64 - 1.0) as f32;
+        let y = (1.0 - 2.0 * position.y / size.height as f64) as f32;
+        let unproject = |depth| {
+            Point3::from_homogeneous(inverse * Vector4::new(x, y, depth, 1.0))
+        };
+
+        // the far plane can be at infinity, so aim at a point halfway there
+        let near_depth = if texture::Texture::REVERSE_Z { 1.0 } else { 0.0 };
+        let origin = unproject(near_depth);
+        Some(Self {
+            origin,
+            direction: (unproject(0.5) - origin).normalize(),
+        })
+    }
+
+    pub fn at(&self, t: f32) -> Point3<f32> {
+        self.origin + self.direction * t
+    }
+
+    // distances along the transformed ray match the ones along this ray
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Self {
+        Self {
+            origin: matrix.transform_point(self.origin),
+            direction: matrix.transform_vector(self.direction),
+        }
+    }
+
+    // distance to where the ray enters the box, 0 when it starts inside
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let mut near = 0.0f32;
+        let mut far = f32::INFINITY;
+        for axis in 0..3 {
+            // dividing by a zero direction gives infinities that still work
+            let inverse = 1.0 / self.direction[axis];
+            let t0 = (aabb.min[axis] - self.origin[axis]) * inverse;
+            let t1 = (aabb.max[axis] - self.origin[axis]) * inverse;
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        (near <= far).then_some(near)
+    }
+
+    // Möller-Trumbore, hits both sides of the triangle
+    pub fn intersect_triangle(
+        &self,
+        a: Point3<f32>,
+        b: Point3<f32>,
+        c: Point3<f32>,
+    ) -> Option<f32> {
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = self.direction.cross(edge2);
+        let determinant = edge1.dot(p);
+        if determinant.abs() < f32::EPSILON {
+            // parallel to the triangle
+            return None;
+        }
+        let inverse = 1.0 / determinant;
+        let to_origin = self.origin - a;
+        let u = to_origin.dot(p) * inverse;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = to_origin.cross(edge1);
+        let v = self.direction.dot(q) * inverse;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = edge2.dot(q) * inverse;
+        (t >= 0.0).then_some(t)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    pub instance: usize,
+    pub mesh: usize,
+    // along the ray, in world units
+    pub distance: f32,
+    pub point: Point3<f32>,
+}
+
+// distance to the closest triangle of the mesh closer than max_distance,
+// with the ray already in the mesh's model space
+fn intersect_mesh(local_ray: &Ray, mesh: &Mesh, max_distance: f32) -> Option<f32> {
+    match local_ray.intersect_aabb(&mesh.bounds) {
+        Some(distance) if distance < max_distance => {}
+        _ => return None,
+    }
+    mesh.indices
+        .chunks(3)
+        .filter_map(|c| local_ray.intersect_triangle(
+            mesh.positions[c[0] as usize],
+            mesh.positions[c[1] as usize],
+            mesh.positions[c[2] as usize],
+        ))
+        .filter(|&distance| distance < max_distance)
+        .min_by(f32::total_cmp)
+}
+
+fn local_ray(ray: &Ray, instance: &Instance) -> Option<Ray> {
+    let model: Matrix4<f32> = instance.to_raw().model.into();
+    Some(ray.transform(&model.invert()?))
+}
+
+// The closest triangle the ray hits across every mesh of every instance.
+// The ray is moved into each instance's model space, where the mesh bounds
+// rule out most meshes before their triangles are tested.
+pub fn pick(ray: &Ray, instances: &[Instance], meshes: &[Mesh]) -> Option<Hit> {
+    let mut closest: Option<Hit> = None;
+    for (instance_index, instance) in instances.iter().enumerate() {
+        let Some(local_ray) = local_ray(ray, instance) else {
+            continue;
+        };
+        for (mesh_index, mesh) in meshes.iter().enumerate() {
+            let best = closest.map_or(f32::INFINITY, |hit| hit.distance);
+            if let Some(distance) = intersect_mesh(&local_ray, mesh, best) {
+                closest = Some(Hit {
+                    instance: instance_index,
+                    mesh: mesh_index,
+                    distance,
+                    point: ray.at(distance),
+                });
+            }
+        }
+    }
+    closest
+}
+
+// where the ray hits one particular mesh instance, for when something else
+// already knows what's under the cursor
+pub fn pick_mesh(
+    ray: &Ray,
+    instances: &[Instance],
+    meshes: &[Mesh],
+    instance: usize,
+    mesh: usize,
+) -> Option<Hit> {
+    let local_ray = local_ray(ray, instances.get(instance)?)?;
+    let distance = intersect_mesh(&local_ray, meshes.get(mesh)?, f32::INFINITY)?;
+    Some(Hit {
+        instance,
+        mesh,
+        distance,
+        point: ray.at(distance),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Projection;
+
+    fn ray(origin: [f32; 3], direction: [f32; 3]) -> Ray {
+        Ray {
+            origin: Point3::from(origin),
+            direction: Vector3::from(direction).normalize(),
+        }
+    }
+
+    fn unit_box() -> Aabb {
+        Aabb {
+            min: Point3::new(-1.0, -1.0, -1.0),
+            max: Point3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn rays_enter_boxes_at_the_near_face() {
+        let hit = ray([0.0, 0.0, 5.0], [0.0, 0.0, -1.0]).intersect_aabb(&unit_box());
+        assert_eq!(hit, Some(4.0));
+        // axis aligned rays divide by zero on the other axes
+        let hit = ray([0.5, 0.5, 5.0], [0.0, 0.0, -1.0]).intersect_aabb(&unit_box());
+        assert_eq!(hit, Some(4.0));
+        let inside = ray([0.0, 0.0, 0.0], [1.0, 1.0, 0.0]).intersect_aabb(&unit_box());
+        assert_eq!(inside, Some(0.0));
+    }
+
+    #[test]
+    fn rays_miss_boxes_beside_or_behind_them() {
+        assert!(ray([0.0, 2.0, 5.0], [0.0, 0.0, -1.0]).intersect_aabb(&unit_box()).is_none());
+        assert!(ray([0.0, 0.0, 5.0], [0.0, 0.0, 1.0]).intersect_aabb(&unit_box()).is_none());
+        assert!(ray([3.0, 0.0, 5.0], [0.0, 1.0, -1.0]).intersect_aabb(&unit_box()).is_none());
+    }
+
+    #[test]
+    fn rays_hit_triangles_from_either_side() {
+        let (a, b, c) = (
+            Point3::new(-1.0, -1.0, 0.0),
+            Point3::new(1.0, -1.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        );
+        let front = ray([0.0, 0.0, 2.0], [0.0, 0.0, -1.0]).intersect_triangle(a, b, c);
+        let back = ray([0.0, 0.0, -3.0], [0.0, 0.0, 1.0]).intersect_triangle(a, b, c);
+        assert_eq!(front, Some(2.0));
+        assert_eq!(back, Some(3.0));
+    }
+
+    #[test]
+    fn rays_miss_triangles_outside_parallel_or_behind() {
+        let (a, b, c) = (
+            Point3::new(-1.0, -1.0, 0.0),
+            Point3::new(1.0, -1.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        );
+        // past the slanted edge
+        assert!(ray([0.9, 0.9, 2.0], [0.0, 0.0, -1.0]).intersect_triangle(a, b, c).is_none());
+        assert!(ray([0.0, 0.0, 2.0], [1.0, 0.0, 0.0]).intersect_triangle(a, b, c).is_none());
+        assert!(ray([0.0, 0.0, 2.0], [0.0, 0.0, 1.0]).intersect_triangle(a, b, c).is_none());
+    }
+
+    #[test]
+    fn distances_survive_moving_into_model_space() {
+        let instance = Instance {
+            position: Vector3::new(0.0, 0.0, -10.0),
+            rotation: Quaternion::from_angle_y(Deg(30.0)),
+            scale: Vector3::new(3.0, 2.0, 0.5),
+            ..Default::default()
+        };
+        let world = ray([0.0, 0.0, 0.0], [0.0, 0.0, -1.0]);
+        let local = local_ray(&world, &instance).unwrap();
+        let distance = local.intersect_aabb(&unit_box()).unwrap();
+        let model: Matrix4<f32> = instance.to_raw().model.into();
+        let entry = model.transform_point(local.at(distance));
+        assert!((world.at(distance) - entry).magnitude() < 1e-4);
+        assert!(distance > 0.0 && distance < 10.0);
+    }
+
+    #[test]
+    fn screen_rays_start_on_the_near_plane() {
+        let projection = Projection::new(800, 600, Deg(60.0), 0.5, 100.0);
+        let view = Matrix4::look_to_rh(
+            Point3::new(1.0, 2.0, 3.0),
+            -Vector3::unit_z(),
+            Vector3::unit_y(),
+        );
+        let centre = Ray::from_screen(
+            PhysicalPosition::new(400.0, 300.0),
+            PhysicalSize::new(800, 600),
+            view,
+            &projection,
+        )
+        .unwrap();
+        assert!((centre.origin - Point3::new(1.0, 2.0, 2.5)).magnitude() < 1e-4);
+        assert!((centre.direction + Vector3::unit_z()).magnitude() < 1e-4);
+
+        let corner = Ray::from_screen(
+            PhysicalPosition::new(0.0, 0.0),
+            PhysicalSize::new(800, 600),
+            view,
+            &projection,
+        )
+        .unwrap();
+        assert!(corner.direction.x < 0.0 && corner.direction.y > 0.0);
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use instant::Duration;
+use tracing::warn;
+use winit::dpi::PhysicalPosition;
+use winit::event::{ModifiersState, MouseButton, MouseScrollDelta};
+
+use crate::input::{self, GamepadAnalog, GamepadButton, InputEvent};
+
+// Input logs are plain text with a line per input, in the order they
+// reached State::input, and a frame line with the time step of every
+// State::update in between:
+//
+//   cursor <x> <y>
+//   key <winit key name> <pressed 0/1>
+//   modifiers <bits>
+//   scroll line|pixel <x> <y>
+//   button left|right|middle|<number> <pressed 0/1>
+//   motion <dx> <dy>
+//   focus <focused 0/1>
+//   pad <button> <pressed 0/1>
+//   analog <stick direction or trigger> <amount>
+//   look <dx> <dy>
+//   frame <nanoseconds>
+//
+// Floats are written with as many digits as it takes to read them back
+// exactly, so a replay makes the same updates bit for bit.
+#[derive(Debug, Clone, Copy)]
+enum Record {
+    Input(InputEvent),
+    Frame(Duration),
+}
+
+fn format_input(input: &InputEvent) -> String {
+    let flag = |pressed: bool| if pressed { 1 } else { 0 };
+    match input {
+        InputEvent::CursorMoved(position) => format!("cursor {} {}", position.x, position.y),
+        InputEvent::Key(key, pressed) => format!("key {:?} {}", key, flag(*pressed)),
+        InputEvent::Modifiers(modifiers) => format!("modifiers {}", modifiers.bits()),
+        InputEvent::Scroll(MouseScrollDelta::LineDelta(x, y)) => format!("scroll line {} {}", x, y),
+        InputEvent::Scroll(MouseScrollDelta::PixelDelta(position)) => {
+            format!("scroll pixel {} {}", position.x, position.y)
+        }
+        InputEvent::MouseButton(button, pressed) => {
+            let button = match button {
+                MouseButton::Left => "left".to_string(),
+                MouseButton::Right => "right".to_string(),
+                MouseButton::Middle => "middle".to_string(),
+                MouseButton::Other(number) => number.to_string(),
+            };
+            format!("button {} {}", button, flag(*pressed))
+        }
+        InputEvent::MouseMotion(dx, dy) => format!("motion {} {}", dx, dy),
+        InputEvent::Focused(focused) => format!("focus {}", flag(*focused)),
+        InputEvent::GamepadButton(button, pressed) => {
+            format!("pad {:?} {}", button, flag(*pressed))
+        }
+        InputEvent::GamepadAnalog(analog, amount) => format!("analog {:?} {}", analog, amount),
+        InputEvent::GamepadLook(dx, dy) => format!("look {} {}", dx, dy),
+    }
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let flag = |word: &str| match word {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    };
+    let input = match words.as_slice() {
+        ["frame", nanos] => return Some(Record::Frame(Duration::from_nanos(nanos.parse().ok()?))),
+        ["cursor", x, y] => {
+            InputEvent::CursorMoved(PhysicalPosition::new(x.parse().ok()?, y.parse().ok()?))
+        }
+        ["key", key, pressed] => InputEvent::Key(input::parse_key(key)?, flag(pressed)?),
+        ["modifiers", bits] => InputEvent::Modifiers(ModifiersState::from_bits(bits.parse().ok()?)?),
+        ["scroll", "line", x, y] => {
+            InputEvent::Scroll(MouseScrollDelta::LineDelta(x.parse().ok()?, y.parse().ok()?))
+        }
+        ["scroll", "pixel", x, y] => InputEvent::Scroll(MouseScrollDelta::PixelDelta(
+            PhysicalPosition::new(x.parse().ok()?, y.parse().ok()?),
+        )),
+        ["button", button, pressed] => {
+            let button = match *button {
+                "left" => MouseButton::Left,
+                "right" => MouseButton::Right,
+                "middle" => MouseButton::Middle,
+                number => MouseButton::Other(number.parse().ok()?),
+            };
+            InputEvent::MouseButton(button, flag(pressed)?)
+        }
+        ["motion", dx, dy] => InputEvent::MouseMotion(dx.parse().ok()?, dy.parse().ok()?),
+        ["focus", focused] => InputEvent::Focused(flag(focused)?),
+        ["pad", button, pressed] => {
+            InputEvent::GamepadButton(GamepadButton::parse(button)?, flag(pressed)?)
+        }
+        ["analog", analog, amount] => {
+            InputEvent::GamepadAnalog(GamepadAnalog::parse(analog)?, amount.parse().ok()?)
+        }
+        ["look", dx, dy] => InputEvent::GamepadLook(dx.parse().ok()?, dy.parse().ok()?),
+        _ => return None,
+    };
+    Some(Record::Input(input))
+}
+
+// Writes an input log as the app runs. Every frame is flushed, so the log
+// survives a crash. Stops with a warning if writing ever fails.
+pub struct InputRecorder {
+    writer: Option<BufWriter<File>>,
+}
+
+impl InputRecorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            writer: Some(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    pub fn record(&mut self, input: &InputEvent) {
+        self.write(&format_input(input), false);
+    }
+
+    pub fn frame(&mut self, dt: Duration) {
+        self.write(&format!("frame {}", dt.as_nanos()), true);
+    }
+
+    fn write(&mut self, line: &str, flush: bool) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let result = writeln!(writer, "{}", line)
+            .and_then(|_| if flush { writer.flush() } else { Ok(()) });
+        if let Err(e) = result {
+            warn!("stopped recording input: {}", e);
+            self.writer = None;
+        }
+    }
+}
+
+// Reads an input log back a frame at a time.
+pub struct InputReplay {
+    records: VecDeque<Record>,
+}
+
+impl InputReplay {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let records = text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| parse_record(line).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad input log entry on line {}", number + 1),
+            )))
+            .collect::<io::Result<_>>()?;
+        Ok(Self { records })
+    }
+
+    // the inputs that came before the next update and the time step it was
+    // given, None once the log runs out
+    pub fn next_frame(&mut self) -> Option<(Vec<InputEvent>, Duration)> {
+        let mut inputs = Vec::new();
+        while let Some(record) = self.records.pop_front() {
+            match record {
+                Record::Input(input) => inputs.push(input),
+                Record::Frame(dt) => return Some((inputs, dt)),
+            }
+        }
+        // inputs after the last frame never reached an update
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use winit::event::VirtualKeyCode;
+
+    fn inputs() -> Vec<InputEvent> {
+        vec![
+            InputEvent::CursorMoved(PhysicalPosition::new(12.5, 0.1 + 0.2)),
+            InputEvent::Key(VirtualKeyCode::W, true),
+            InputEvent::Key(VirtualKeyCode::F11, false),
+            InputEvent::Modifiers(ModifiersState::CTRL | ModifiersState::SHIFT),
+            InputEvent::Scroll(MouseScrollDelta::LineDelta(0.0, -1.0)),
+            InputEvent::Scroll(MouseScrollDelta::PixelDelta(PhysicalPosition::new(1.0, 1.0 / 3.0))),
+            InputEvent::MouseButton(MouseButton::Left, true),
+            InputEvent::MouseButton(MouseButton::Other(7), false),
+            InputEvent::MouseMotion(-0.000_123, 1e10),
+            InputEvent::Focused(false),
+            InputEvent::GamepadButton(GamepadButton::LeftBumper, true),
+            InputEvent::GamepadAnalog(GamepadAnalog::LeftStickUp, 0.1 + 0.2),
+            InputEvent::GamepadLook(1.0 / 3.0, -0.5),
+        ]
+    }
+
+    #[test]
+    fn inputs_round_trip_exactly() {
+        for input in inputs() {
+            let line = format_input(&input);
+            match parse_record(&line) {
+                Some(Record::Input(parsed)) => assert_eq!(parsed, input, "{}", line),
+                record => panic!("{:?} parsed as {:?}", line, record),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_rejects_bad_records() {
+        for line in [
+            "",
+            "key Nope 1",
+            "key W 2",
+            "cursor 1",
+            "frame -1",
+            "scroll up 1 2",
+            "pad PadSouth 1",
+            "analog LeftStickUp",
+        ] {
+            assert!(parse_record(line).is_none(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn recorded_logs_replay_frame_by_frame() {
+        let path = std::env::temp_dir()
+            .join(format!("replay-test-{}.log", std::process::id()));
+        let inputs = inputs();
+        {
+            let mut recorder = InputRecorder::create(&path).unwrap();
+            recorder.record(&inputs[0]);
+            recorder.record(&inputs[1]);
+            recorder.frame(Duration::from_nanos(16_666_667));
+            recorder.frame(Duration::from_millis(5));
+            // never reaches an update
+            recorder.record(&inputs[2]);
+        }
+        let mut replay = InputReplay::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            replay.next_frame(),
+            Some((inputs[..2].to_vec(), Duration::from_nanos(16_666_667))),
+        );
+        assert_eq!(replay.next_frame(), Some((Vec::new(), Duration::from_millis(5))));
+        assert_eq!(replay.next_frame(), None);
+    }
+}
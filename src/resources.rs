@@ -1,7 +1,9 @@
+use std::collections::HashSet;
 use std::io::{BufReader, Cursor};
 use wgpu::util::DeviceExt;
 
-use crate::{model, texture};
+use crate::{lod, model, texture};
+use crate::culling::Aabb;
 
 pub async fn load_string(file_name: &str) -> String {
     let path = std::path::Path::new(env!("OUT_DIR"))
@@ -69,11 +71,14 @@ pub async fn load_model(
             queue
         ).await;
 
+        let alpha_mode = model::AlphaMode::from_mtl(&m, diffuse_texture.has_alpha);
         materials.push(model::Material::new(
             device,
             &m.name,
             diffuse_texture,
             normal_texture,
+            alpha_mode,
+            m.dissolve,
             layout,
         ))
     }
@@ -172,15 +177,75 @@ pub async fn load_model(
 
         for (i, n) in triangles_included.into_iter().enumerate() {
             let denom = 1.0 / n as f32;
-            let mut v = &mut vertices[i];
+            let v = &mut vertices[i];
             v.tangent = (cgmath::Vector3::from(v.tangent) * denom).into();
             v.bitangent = (cgmath::Vector3::from(v.bitangent) * denom).into();
         }
 
+        (m.name, vertices, m.mesh.indices, m.mesh.material_id.unwrap_or(0))
+    }).collect::<Vec<_>>();
+
+    // meshes named `<name>_LOD<n>` are authored LODs of `<name>`
+    let mut groups: Vec<(String, Vec<_>)> = Vec::new();
+    for (name, vertices, indices, material) in meshes {
+        let (base_name, level) = lod::split_lod_name(&name);
+        let lod = (level, vertices, indices, material);
+        match groups.iter_mut().find(|(name, _)| name == base_name) {
+            Some((_, lods)) => lods.push(lod),
+            None => groups.push((base_name.to_string(), vec![lod])),
+        }
+    }
+
+    let meshes = groups.into_iter().map(|(_, mut lods)| {
+        lods.sort_by_key(|(level, ..)| *level);
+        let (_, vertices, indices, material) = lods.remove(0);
+
+        let bounds = Aabb::from_points(
+            vertices.iter().map(|v| v.position.into())
+        ).unwrap_or(Aabb {
+            min: cgmath::Point3::new(0.0, 0.0, 0.0),
+            max: cgmath::Point3::new(0.0, 0.0, 0.0),
+        });
+
+        // without authored LODs coarser ones are generated, they reuse the
+        // full detail vertices
+        let mut all_vertices = vertices;
+        let extra_lods = if lods.is_empty() {
+            lod::generate_lods(&all_vertices, &indices, &bounds)
+                .into_iter()
+                .map(|indices| (0, indices))
+                .collect::<Vec<_>>()
+        } else {
+            lods.into_iter().map(|(_, lod_vertices, indices, _)| {
+                let base_vertex = all_vertices.len();
+                all_vertices.extend(lod_vertices);
+                (base_vertex, indices)
+            }).collect::<Vec<_>>()
+        };
+
+        let num_elements = indices.len() as u32;
+        let edge_indices = edge_indices(&indices);
+        let lod_count = extra_lods.len() + 1;
+        let mut all_indices = indices;
+        let mut mesh_lods = vec![model::Lod {
+            indices: 0..num_elements,
+            base_vertex: 0,
+            min_screen_size: lod::min_screen_size(0, lod_count),
+        }];
+        for (i, (base_vertex, indices)) in extra_lods.into_iter().enumerate() {
+            let start = all_indices.len() as u32;
+            all_indices.extend(indices);
+            mesh_lods.push(model::Lod {
+                indices: start..all_indices.len() as u32,
+                base_vertex: base_vertex as i32,
+                min_screen_size: lod::min_screen_size(i + 1, lod_count),
+            });
+        }
+
         let vertex_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&vertices),
+                label: Some(&format!("{:?} Vertex Buffer", file_name)),
+                contents: bytemuck::cast_slice(&all_vertices),
                 usage: wgpu::BufferUsages::VERTEX,
             }
         );
@@ -188,7 +253,15 @@ pub async fn load_model(
         let index_buffer = device.create_buffer_init(
             &wgpu::util::BufferInitDescriptor {
                 label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
+                contents: bytemuck::cast_slice(&all_indices),
+                usage: wgpu::BufferUsages::INDEX,
+            }
+        );
+
+        let edge_index_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Edge Index Buffer", file_name)),
+                contents: bytemuck::cast_slice(&edge_indices),
                 usage: wgpu::BufferUsages::INDEX,
             }
         );
@@ -197,11 +270,33 @@ pub async fn load_model(
             name: file_name.to_string(),
             vertex_buffer,
             index_buffer,
-            num_elements: m.mesh.indices.len() as u32,
-            material: m.mesh.material_id.unwrap_or(0),
+            num_elements,
+            lods: mesh_lods,
+            edge_index_buffer,
+            num_edge_elements: edge_indices.len() as u32,
+            bounds,
+            positions: all_vertices.iter().map(|v| v.position.into()).collect(),
+            indices: all_indices[..num_elements as usize].to_vec(),
+            material,
         }
 
     }).collect::<Vec<_>>();
 
     model::Model { meshes, materials }
-}
\ No newline at end of file
+}
+
+
+// turns a triangle list into a line list, emitting each shared edge once
+fn edge_indices(indices: &[u32]) -> Vec<u32> {
+    let mut seen = HashSet::new();
+    let mut edges = Vec::new();
+    for c in indices.chunks(3) {
+        for (a, b) in [(c[0], c[1]), (c[1], c[2]), (c[2], c[0])] {
+            if seen.insert((a.min(b), a.max(b))) {
+                edges.push(a);
+                edges.push(b);
+            }
+        }
+    }
+    edges
+}
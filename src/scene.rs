@@ -0,0 +1,358 @@
+use cgmath::*;
+use serde::{Deserialize, Serialize};
+
+use crate::Instance;
+
+// What a node puts in the world at its transform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Attachment {
+    // an instance of the model
+    Model,
+    Light,
+    Camera,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    local: Instance,
+    world: Instance,
+    // local has changed since world was worked out
+    dirty: bool,
+    attachments: Vec<Attachment>,
+    // its place among the model instances, with a Model attachment
+    instance: Option<usize>,
+    // degrees per second around its own y axis
+    spin: f32,
+}
+
+// Nodes placed relative to their parents. Changing a node only marks it
+// dirty, update then works out the world transforms of dirty nodes and
+// everything below them in one pass from the roots. World transforms are
+// stale until then. Removed nodes leave their slot empty, so the ids of
+// the rest stay put.
+#[derive(Debug, Default)]
+pub struct SceneGraph {
+    nodes: Vec<Option<Node>>,
+    roots: Vec<NodeId>,
+    // the nodes with a Model attachment, in instance order
+    models: Vec<NodeId>,
+}
+
+impl SceneGraph {
+    fn node(&self, id: NodeId) -> &Node {
+        self.nodes[id.0].as_ref().expect("node has been removed")
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        self.nodes[id.0].as_mut().expect("node has been removed")
+    }
+
+    pub fn add<S: Into<String>>(
+        &mut self,
+        name: S,
+        parent: Option<NodeId>,
+        local: Instance,
+    ) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Some(Node {
+            name: name.into(),
+            parent,
+            children: Vec::new(),
+            world: local.clone(),
+            local,
+            dirty: true,
+            attachments: Vec::new(),
+            instance: None,
+            spin: 0.0,
+        }));
+        match parent {
+            Some(parent) => self.node_mut(parent).children.push(id),
+            None => self.roots.push(id),
+        }
+        id
+    }
+
+    // a Model attachment makes the node the last instance
+    pub fn attach(&mut self, node: NodeId, attachment: Attachment) {
+        if attachment == Attachment::Model && self.node(node).instance.is_none() {
+            self.node_mut(node).instance = Some(self.models.len());
+            self.models.push(node);
+        }
+        self.node_mut(node).attachments.push(attachment);
+    }
+
+    pub fn attachments(&self, node: NodeId) -> &[Attachment] {
+        &self.node(node).attachments
+    }
+
+    // Takes the node and everything below it out of the scene. Returns the
+    // instance indices they had, in order, the instances after them move
+    // down to fill the gaps.
+    pub fn remove(&mut self, node: NodeId) -> Vec<usize> {
+        match self.node(node).parent {
+            Some(parent) => self.node_mut(parent).children.retain(|&child| child != node),
+            None => self.roots.retain(|&root| root != node),
+        }
+
+        let mut removed = Vec::new();
+        let mut stack = vec![node];
+        while let Some(id) = stack.pop() {
+            let node = self.nodes[id.0].take().expect("node has been removed");
+            stack.extend(node.children);
+            removed.extend(node.instance);
+        }
+        removed.sort_unstable();
+
+        if !removed.is_empty() {
+            self.models.retain(|model| self.nodes[model.0].is_some());
+            for (index, model) in self.models.iter().enumerate() {
+                if let Some(node) = &mut self.nodes[model.0] {
+                    node.instance = Some(index);
+                }
+            }
+        }
+        removed
+    }
+
+    // false once the node has been removed
+    pub fn contains(&self, node: NodeId) -> bool {
+        self.nodes[node.0].is_some()
+    }
+
+    // every node, in the order they were added
+    pub fn nodes(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes.iter()
+            .enumerate()
+            .filter(|(_, node)| node.is_some())
+            .map(|(i, _)| NodeId(i))
+    }
+
+    // keeps the node's local transform, so it moves with its new parent.
+    // Parenting a node to itself or anything below it is refused.
+    pub fn set_parent(&mut self, node: NodeId, parent: Option<NodeId>) -> bool {
+        let mut ancestor = parent;
+        while let Some(id) = ancestor {
+            if id == node {
+                return false;
+            }
+            ancestor = self.node(id).parent;
+        }
+
+        match self.node(node).parent {
+            Some(old) => self.node_mut(old).children.retain(|&child| child != node),
+            None => self.roots.retain(|&root| root != node),
+        }
+        match parent {
+            Some(parent) => self.node_mut(parent).children.push(node),
+            None => self.roots.push(node),
+        }
+        let node = self.node_mut(node);
+        node.parent = parent;
+        node.dirty = true;
+        true
+    }
+
+    pub fn name(&self, node: NodeId) -> &str {
+        &self.node(node).name
+    }
+
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.nodes()
+            .find(|&node| self.name(node) == name)
+    }
+
+    pub fn parent(&self, node: NodeId) -> Option<NodeId> {
+        self.node(node).parent
+    }
+
+    pub fn local(&self, node: NodeId) -> &Instance {
+        &self.node(node).local
+    }
+
+    pub fn set_local(&mut self, node: NodeId, local: Instance) {
+        let node = self.node_mut(node);
+        node.local = local;
+        node.dirty = true;
+    }
+
+    pub fn world(&self, node: NodeId) -> &Instance {
+        &self.node(node).world
+    }
+
+    pub fn spin(&self, node: NodeId) -> f32 {
+        self.node(node).spin
+    }
+
+    pub fn set_spin(&mut self, node: NodeId, spin: f32) {
+        self.node_mut(node).spin = spin;
+    }
+
+    // the node holding the model instance at `index`
+    pub fn instance_node(&self, index: usize) -> NodeId {
+        self.models[index]
+    }
+
+    // turns the spinning nodes by `dt` seconds worth
+    pub fn animate(&mut self, dt: f32) {
+        for node in self.nodes.iter_mut().flatten().filter(|node| node.spin != 0.0) {
+            let turn = Quaternion::from_angle_y(Deg(node.spin * dt));
+            node.local.rotation = (node.local.rotation * turn).normalize();
+            node.dirty = true;
+        }
+    }
+
+    // every node with the attachment, in the order they were added
+    pub fn attached(&self, attachment: Attachment) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes()
+            .filter(move |&node| self.attachments(node).contains(&attachment))
+    }
+
+    // The world transforms of the model instances, in the order they were
+    // attached. Their positions in here are the instance indices used by
+    // culling and picking.
+    pub fn instances(&self) -> Vec<Instance> {
+        self.models.iter()
+            .map(|&node| self.world(node).clone())
+            .collect()
+    }
+
+    // brings the world transforms up to date, returns the indices of the
+    // model instances that moved
+    pub fn update(&mut self) -> Vec<usize> {
+        let mut moved_instances = Vec::new();
+        let mut stack = self.roots.iter()
+            .rev()
+            .map(|&root| (root, false))
+            .collect::<Vec<_>>();
+        while let Some((id, parent_moved)) = stack.pop() {
+            let moved = parent_moved || self.node(id).dirty;
+            if moved {
+                let parent_world = self.node(id).parent
+                    .map(|parent| self.node(parent).world.clone());
+                let node = self.node_mut(id);
+                node.world = match parent_world {
+                    Some(parent_world) => node.local.within(&parent_world),
+                    None => node.local.clone(),
+                };
+                node.dirty = false;
+                moved_instances.extend(node.instance);
+            }
+            stack.extend(self.node(id).children.iter().rev().map(|&child| (child, moved)));
+        }
+        moved_instances
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> Instance {
+        Instance {
+            position: Vector3::new(x, 0.0, 0.0),
+            ..Default::default()
+        }
+    }
+
+    fn model(scene: &mut SceneGraph, name: &str, parent: Option<NodeId>, x: f32) -> NodeId {
+        let node = scene.add(name, parent, at(x));
+        scene.attach(node, Attachment::Model);
+        node
+    }
+
+    fn instance_names(scene: &SceneGraph) -> Vec<&str> {
+        (0..scene.instances().len())
+            .map(|index| scene.name(scene.instance_node(index)))
+            .collect()
+    }
+
+    #[test]
+    fn children_follow_their_parents() {
+        let mut scene = SceneGraph::default();
+        let parent = model(&mut scene, "parent", None, 1.0);
+        let child = model(&mut scene, "child", Some(parent), 2.0);
+        assert_eq!(scene.update(), [0, 1]);
+        assert_eq!(scene.world(child).world_position(), Vector3::new(3.0, 0.0, 0.0));
+
+        // moving the parent moves the child, untouched nodes stay put
+        let other = model(&mut scene, "other", None, 0.0);
+        scene.update();
+        scene.set_local(parent, at(5.0));
+        assert_eq!(scene.update(), [0, 1]);
+        assert_eq!(scene.world(child).world_position(), Vector3::new(7.0, 0.0, 0.0));
+        assert_eq!(scene.world(other).world_position(), Vector3::new(0.0, 0.0, 0.0));
+        assert!(scene.update().is_empty());
+    }
+
+    #[test]
+    fn remove_takes_the_whole_subtree() {
+        let mut scene = SceneGraph::default();
+        let a = model(&mut scene, "a", None, 0.0);
+        let b = model(&mut scene, "b", None, 1.0);
+        let b_child = scene.add("b child", Some(b), at(1.0));
+        let b_grandchild = model(&mut scene, "b grandchild", Some(b_child), 1.0);
+        let c = model(&mut scene, "c", Some(a), 2.0);
+        scene.update();
+
+        assert_eq!(scene.remove(b), [1, 2]);
+        for node in [b, b_child, b_grandchild] {
+            assert!(!scene.contains(node));
+        }
+        assert!(scene.find("b grandchild").is_none());
+        assert_eq!(scene.nodes().collect::<Vec<_>>(), [a, c]);
+    }
+
+    #[test]
+    fn remove_moves_later_instances_down() {
+        let mut scene = SceneGraph::default();
+        let a = model(&mut scene, "a", None, 0.0);
+        let b = model(&mut scene, "b", None, 1.0);
+        model(&mut scene, "c", Some(a), 2.0);
+        model(&mut scene, "d", None, 3.0);
+        scene.update();
+
+        assert_eq!(scene.remove(b), [1]);
+        assert_eq!(instance_names(&scene), ["a", "c", "d"]);
+        let positions = scene.instances().iter()
+            .map(|instance| instance.world_position().x)
+            .collect::<Vec<_>>();
+        assert_eq!(positions, [0.0, 2.0, 3.0]);
+
+        // moving a reports the instance's new index
+        scene.set_local(a, at(10.0));
+        assert_eq!(scene.update(), [0, 1]);
+    }
+
+    #[test]
+    fn removing_a_child_leaves_the_parent() {
+        let mut scene = SceneGraph::default();
+        let parent = scene.add("parent", None, at(0.0));
+        let child = model(&mut scene, "child", Some(parent), 1.0);
+        let sibling = model(&mut scene, "sibling", Some(parent), 2.0);
+        scene.update();
+
+        assert_eq!(scene.remove(child), [0]);
+        assert!(scene.contains(parent));
+        assert_eq!(instance_names(&scene), ["sibling"]);
+        // the removed child isn't visited when the parent moves
+        scene.set_local(parent, at(1.0));
+        assert_eq!(scene.update(), [0]);
+        assert_eq!(scene.world(sibling).world_position(), Vector3::new(3.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn nodes_without_models_have_no_instances_to_remove() {
+        let mut scene = SceneGraph::default();
+        let light = scene.add("light", None, at(0.0));
+        scene.attach(light, Attachment::Light);
+        model(&mut scene, "model", None, 1.0);
+        assert!(scene.remove(light).is_empty());
+        assert_eq!(instance_names(&scene), ["model"]);
+    }
+}
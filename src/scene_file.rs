@@ -0,0 +1,399 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+
+use cgmath::*;
+use ron::extensions::Extensions;
+use serde::{Deserialize, Serialize};
+
+use crate::model::{AlphaMode, Material};
+use crate::scene::{Attachment, SceneGraph};
+use crate::{resources, Instance};
+
+fn ron_options() -> ron::Options {
+    ron::Options::default().with_default_extension(Extensions::IMPLICIT_SOME)
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+fn white() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn is_one(value: &[f32; 3]) -> bool {
+    *value == [1.0; 3]
+}
+
+// turned around y, then its own x, then its own z, the way cameras yaw,
+// pitch and roll
+fn rotation_from_degrees([x, y, z]: [f32; 3]) -> Quaternion<f32> {
+    Quaternion::from_angle_y(Deg(y))
+        * Quaternion::from_angle_x(Deg(x))
+        * Quaternion::from_angle_z(Deg(z))
+}
+
+// the angles rotation_from_degrees takes, rounded so whole angles stay whole
+fn degrees_from_rotation(rotation: Quaternion<f32>) -> [f32; 3] {
+    let m = Matrix3::from(rotation);
+    let cos_x = m[0][1].hypot(m[1][1]);
+    let x = (-m[2][1]).atan2(cos_x);
+    let (y, z) = if cos_x > 1e-4 {
+        (m[2][0].atan2(m[2][2]), m[0][1].atan2(m[1][1]))
+    } else {
+        // looking straight up or down, roll and yaw turn the same way
+        ((-m[0][2]).atan2(m[0][0]), 0.0)
+    };
+    // adding zero turns -0 into 0, and a half turn is written as 180
+    let degrees = |angle: f32| match (Deg::from(Rad(angle)).0 * 1e4).round() / 1e4 + 0.0 {
+        -180.0 => 180.0,
+        angle => angle,
+    };
+    [degrees(x), degrees(y), degrees(z)]
+}
+
+// A scene written in RON for people to edit by hand. Only the names of
+// nodes and materials are required, everything else has a default:
+//
+//   SceneDescription(
+//       model: "cube.obj",
+//       materials: [
+//           MaterialDescription(name: "Material.001", alpha_mode: Mask(0.5)),
+//       ],
+//       nodes: [
+//           NodeDescription(name: "pivot", spin: 60.0),
+//           NodeDescription(
+//               name: "light",
+//               parent: "pivot",
+//               position: (2.0, 2.0, 2.0),
+//               attachments: [Light],
+//           ),
+//       ],
+//   )
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SceneDescription {
+    // A single model from the res folder. Every Model attachment is an
+    // instance of it, a scene can't mix models.
+    pub model: String,
+    // changes to the model's own materials
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub materials: Vec<MaterialDescription>,
+    #[serde(default = "white")]
+    pub light_color: [f32; 3],
+    #[serde(default)]
+    pub nodes: Vec<NodeDescription>,
+}
+
+// Anything left out keeps what the model's MTL file says.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaterialDescription {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diffuse_texture: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normal_texture: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alpha_mode: Option<AlphaMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opacity: Option<f32>,
+}
+
+impl MaterialDescription {
+    pub async fn apply(
+        &self,
+        material: Material,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Material {
+        let diffuse_texture = match &self.diffuse_texture {
+            Some(file_name) => resources::load_texture(file_name, false, device, queue).await,
+            None => material.diffuse_texture,
+        };
+        let normal_texture = match &self.normal_texture {
+            Some(file_name) => resources::load_texture(file_name, true, device, queue).await,
+            None => material.normal_texture,
+        };
+        Material::new(
+            device,
+            &material.name,
+            diffuse_texture,
+            normal_texture,
+            self.alpha_mode.unwrap_or(material.alpha_mode),
+            self.opacity.unwrap_or(material.opacity),
+            layout,
+        )
+    }
+}
+
+// Placed relative to the parent with that name. Rotations are in degrees,
+// around y first, then x, then z.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeDescription {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub position: [f32; 3],
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub rotation: [f32; 3],
+    #[serde(default = "white", skip_serializing_if = "is_one")]
+    pub scale: [f32; 3],
+    // multiplies the colour of a Model attachment
+    #[serde(default = "white", skip_serializing_if = "is_one")]
+    pub tint: [f32; 3],
+    // index into the model's materials for a Model attachment to use
+    // instead of its meshes' own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub material: Option<usize>,
+    // degrees per second around its own y axis
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub spin: f32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+}
+
+impl NodeDescription {
+    fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            parent: None,
+            position: [0.0; 3],
+            rotation: [0.0; 3],
+            scale: [1.0; 3],
+            tint: [1.0; 3],
+            material: None,
+            spin: 0.0,
+            attachments: Vec::new(),
+        }
+    }
+
+    fn local(&self) -> Instance {
+        Instance {
+            position: self.position.into(),
+            rotation: rotation_from_degrees(self.rotation),
+            scale: self.scale.into(),
+            tint: self.tint,
+            material: self.material,
+            ..Default::default()
+        }
+    }
+}
+
+// the grid of cubes the app has always started with
+impl Default for SceneDescription {
+    fn default() -> Self {
+        const NUM_INSTANCES_PER_ROW: u32 = 10;
+        const SPACE_BETWEEN: f32 = 3.0;
+
+        let mut nodes = vec![NodeDescription::new("grid")];
+        for z in 0..NUM_INSTANCES_PER_ROW {
+            for x in 0..NUM_INSTANCES_PER_ROW {
+                let x = SPACE_BETWEEN * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
+                let z = SPACE_BETWEEN * (z as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
+                nodes.push(NodeDescription {
+                    parent: Some("grid".to_string()),
+                    position: [x, 0.0, z],
+                    rotation: [0.0, 180.0, 0.0],
+                    attachments: vec![Attachment::Model],
+                    ..NodeDescription::new(format!("instance {}", nodes.len() - 1))
+                });
+            }
+        }
+
+        // the light circles the origin carried by a spinning pivot
+        nodes.push(NodeDescription {
+            spin: 60.0,
+            ..NodeDescription::new("light pivot")
+        });
+        nodes.push(NodeDescription {
+            parent: Some("light pivot".to_string()),
+            position: [2.0, 2.0, 2.0],
+            attachments: vec![Attachment::Light],
+            ..NodeDescription::new("light")
+        });
+
+        // above the grid looking down at it
+        nodes.push(NodeDescription {
+            position: [0.0, 5.0, 10.0],
+            rotation: [-20.0, 0.0, 0.0],
+            attachments: vec![Attachment::Camera],
+            ..NodeDescription::new("camera")
+        });
+
+        Self {
+            model: "cube.obj".to_string(),
+            materials: Vec::new(),
+            light_color: white(),
+            nodes,
+        }
+    }
+}
+
+impl SceneDescription {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(path, self.format()?)
+    }
+
+    fn format(&self) -> io::Result<String> {
+        ron_options()
+            .to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(invalid)
+    }
+
+    fn parse(text: &str) -> io::Result<Self> {
+        ron_options().from_str(text).map_err(invalid)
+    }
+
+    // Parents are looked up by name once every node exists, so they can
+    // come in any order. Names have to be unique for that.
+    pub fn build(&self) -> io::Result<SceneGraph> {
+        let mut names = HashSet::new();
+        if let Some(duplicate) = self.nodes.iter().find(|node| !names.insert(&node.name)) {
+            return Err(invalid(format!("more than one node is called {:?}", duplicate.name)));
+        }
+
+        let mut scene = SceneGraph::default();
+        let ids = self.nodes.iter()
+            .map(|description| {
+                let node = scene.add(description.name.clone(), None, description.local());
+                scene.set_spin(node, description.spin);
+                for &attachment in &description.attachments {
+                    scene.attach(node, attachment);
+                }
+                node
+            })
+            .collect::<Vec<_>>();
+
+        for (description, &node) in self.nodes.iter().zip(&ids) {
+            let Some(parent_name) = &description.parent else {
+                continue;
+            };
+            let parent = scene.find(parent_name).ok_or_else(|| invalid(format!(
+                "node {:?} has an unknown parent {:?}", description.name, parent_name,
+            )))?;
+            if !scene.set_parent(node, Some(parent)) {
+                return Err(invalid(format!(
+                    "node {:?} would end up its own ancestor", description.name,
+                )));
+            }
+        }
+
+        scene.update();
+        Ok(scene)
+    }
+
+    // replaces the nodes with the scene's as they are now
+    pub fn capture(&mut self, scene: &SceneGraph) {
+        self.nodes = scene.nodes()
+            .map(|node| {
+                let local = scene.local(node);
+                NodeDescription {
+                    name: scene.name(node).to_string(),
+                    parent: scene.parent(node).map(|parent| scene.name(parent).to_string()),
+                    position: local.position.into(),
+                    rotation: degrees_from_rotation(local.rotation),
+                    scale: local.scale.into(),
+                    tint: local.tint,
+                    material: local.material,
+                    spin: scene.spin(node),
+                    attachments: scene.attachments(node).to_vec(),
+                }
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str, parent: Option<&str>) -> NodeDescription {
+        NodeDescription {
+            parent: parent.map(str::to_string),
+            ..NodeDescription::new(name)
+        }
+    }
+
+    fn scene(nodes: Vec<NodeDescription>) -> SceneDescription {
+        SceneDescription {
+            nodes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn the_default_scene_survives_saving_and_loading() {
+        let description = SceneDescription::default();
+        let loaded = SceneDescription::parse(&description.format().unwrap()).unwrap();
+        assert_eq!(loaded.model, description.model);
+        assert_eq!(loaded.light_color, description.light_color);
+        assert_eq!(loaded.nodes.len(), description.nodes.len());
+
+        let built = description.build().unwrap();
+        let rebuilt = loaded.build().unwrap();
+        assert_eq!(rebuilt.instances().len(), 100);
+        for (a, b) in built.nodes().zip(rebuilt.nodes()) {
+            assert_eq!(built.name(a), rebuilt.name(b));
+            assert_eq!(
+                built.parent(a).map(|parent| built.name(parent)),
+                rebuilt.parent(b).map(|parent| rebuilt.name(parent)),
+            );
+            assert_eq!(built.attachments(a), rebuilt.attachments(b));
+            assert_eq!(built.spin(a), rebuilt.spin(b));
+            assert_eq!(built.world(a).matrix(), rebuilt.world(b).matrix());
+        }
+    }
+
+    #[test]
+    fn build_rejects_duplicate_names() {
+        let error = scene(vec![node("a", None), node("b", Some("a")), node("a", None)])
+            .build()
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("\"a\""));
+    }
+
+    #[test]
+    fn build_rejects_unknown_parents_and_cycles() {
+        let unknown = scene(vec![node("a", Some("missing"))]).build().unwrap_err();
+        assert_eq!(unknown.kind(), io::ErrorKind::InvalidData);
+        let cycle = scene(vec![node("a", Some("b")), node("b", Some("a"))]).build().unwrap_err();
+        assert_eq!(cycle.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn build_accepts_parents_after_their_children() {
+        let built = scene(vec![node("child", Some("parent")), node("parent", None)])
+            .build()
+            .unwrap();
+        let child = built.find("child").unwrap();
+        assert_eq!(built.parent(child), built.find("parent"));
+    }
+
+    #[test]
+    fn degrees_survive_a_trip_through_a_rotation() {
+        for angles in [
+            [0.0, 0.0, 0.0],
+            [-20.0, 0.0, 0.0],
+            [0.0, 180.0, 0.0],
+            [30.0, 45.0, 60.0],
+            [-45.0, -90.0, 10.0],
+            [60.0, 120.0, -170.0],
+        ] {
+            assert_eq!(degrees_from_rotation(rotation_from_degrees(angles)), angles);
+        }
+        // straight up the roll folds into the yaw
+        let up = degrees_from_rotation(rotation_from_degrees([90.0, 30.0, 0.0]));
+        assert_eq!(up, [90.0, 30.0, 0.0]);
+    }
+}
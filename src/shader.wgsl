@@ -1,26 +1,7 @@
-// Vertex shader
-
-struct CameraUniform {
-    view_pos: vec4<f32>,
-    view_proj: mat4x4<f32>,
-};
-@group(1) @binding(0)
-var<uniform> camera: CameraUniform;
-
-struct Light {
-    position: vec3<f32>,
-    color: vec3<f32>,
-}
-@group(2) @binding(0)
-var<uniform> light: Light;
+// The lit shader with a bind group per material. lit.wgsl goes in front of
+// this.
 
-struct VertexInput {
-    @location(0) position: vec3<f32>,
-    @location(1) tex_coords: vec2<f32>,
-    @location(2) normal: vec3<f32>,
-    @location(3) tangent: vec3<f32>,
-    @location(4) bitangent: vec3<f32>,
-}
+// Vertex shader
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
@@ -28,16 +9,7 @@ struct VertexOutput {
     @location(1) tangent_position: vec3<f32>,
     @location(2) tangent_light_position: vec3<f32>,
     @location(3) tangent_view_position: vec3<f32>,
-};
-
-struct InstanceInput {
-    @location(5) model_matrix_0: vec4<f32>,
-    @location(6) model_matrix_1: vec4<f32>,
-    @location(7) model_matrix_2: vec4<f32>,
-    @location(8) model_matrix_3: vec4<f32>,
-    @location(9) normal_matrix_0: vec3<f32>,
-    @location(10) normal_matrix_1: vec3<f32>,
-    @location(11) normal_matrix_2: vec3<f32>,
+    @location(4) tint: vec3<f32>,
 };
 
 @vertex
@@ -45,39 +17,16 @@ fn vs_main(
     model: VertexInput,
     instance: InstanceInput,
 ) -> VertexOutput {
-    let model_matrix = mat4x4<f32>(
-        instance.model_matrix_0,
-        instance.model_matrix_1,
-        instance.model_matrix_2,
-        instance.model_matrix_3,
-    );
-    let normal_matrix = mat3x3<f32>(
-        instance.normal_matrix_0,
-        instance.normal_matrix_1,
-        instance.normal_matrix_2,
-    );
-
-    // contruct the tangent matrix
-    let world_normal = normalize(normal_matrix * model.normal);
-    let world_tangent = normalize(normal_matrix * model.tangent);
-    let world_bitangent = normalize(normal_matrix * model.bitangent);
-    let tangent_matrix = transpose(
-        mat3x3<f32>(
-            world_tangent,
-            world_bitangent,
-            world_normal,
-        )
-    );
-
-    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    let tangent = tangent_space(model, instance);
 
     var out: VertexOutput;
-    out.clip_position = camera.view_proj * world_position;
+    out.clip_position = tangent.clip_position;
     out.tex_coords = model.tex_coords;
-    out.tangent_position = tangent_matrix * world_position.xyz;
-    out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
-    out.tangent_light_position = tangent_matrix * light.position;
-    
+    out.tangent_position = tangent.position;
+    out.tangent_view_position = tangent.view_position;
+    out.tangent_light_position = tangent.light_position;
+    out.tint = instance.tint;
+
     return out;
 }
 
@@ -93,27 +42,38 @@ var t_normal: texture_2d<f32>;
 @group(0) @binding(3)
 var s_normal: sampler;
 
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let object_colour: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
-
-    let ambient_strength = 0.1;
-    let ambient_color = light.color * ambient_strength;
-
-    // create the lighting vectors
-    let tangent_normal = object_normal.xyz * 2.0 - 1.0;
-    let light_dir = normalize(in.tangent_light_position - in.tangent_position);
-    let view_dir = normalize(in.tangent_view_position - in.tangent_position);
-    let half_dir = normalize(view_dir + light_dir);
+struct Material {
+    opacity: f32,
+    alpha_cutoff: f32,
+}
+@group(0) @binding(4)
+var<uniform> material: Material;
 
-    let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0);
-    let diffuse_color = light.color * diffuse_strength;
+fn shade(in: VertexOutput, object_colour: vec4<f32>) -> vec4<f32> {
+    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
+    let light_color = lighting(
+        object_normal,
+        in.tangent_position,
+        in.tangent_light_position,
+        in.tangent_view_position,
+    );
+    let result = light_color * object_colour.xyz * in.tint;
 
-    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), 32.0);
-    let specular_color = specular_strength * light.color;
+    return vec4(result, object_colour.a * material.opacity);
+}
 
-    let result = (ambient_color + diffuse_color + specular_color) * object_colour.xyz;
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let object_colour: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    return shade(in, object_colour);
+}
 
-    return vec4(result, object_colour.a);
+// alpha tested variant for cutout materials
+@fragment
+fn fs_cutout(in: VertexOutput) -> @location(0) vec4<f32> {
+    let object_colour: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    if (object_colour.a * material.opacity < material.alpha_cutoff) {
+        discard;
+    }
+    return shade(in, object_colour);
 }
@@ -4,10 +4,29 @@ pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
+    pub size: wgpu::Extent3d,
+    // whether the image had an alpha channel, for cutouts to test
+    pub has_alpha: bool,
 }
 
 impl Texture {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+    // Reverse-Z maps the near plane to 1 and an infinitely distant far plane
+    // to 0. Float depth keeps far more precision that way round, so there's
+    // no far clip and no z-fighting at range. The reverse-z feature turns it
+    // on, and with it the projection, depth clear and compare functions.
+    pub const REVERSE_Z: bool = cfg!(feature = "reverse-z");
+    pub const DEPTH_CLEAR: f32 = if Self::REVERSE_Z { 0.0 } else { 1.0 };
+    pub const DEPTH_COMPARE: wgpu::CompareFunction = if Self::REVERSE_Z {
+        wgpu::CompareFunction::Greater
+    } else {
+        wgpu::CompareFunction::Less
+    };
+    pub const DEPTH_COMPARE_EQUAL: wgpu::CompareFunction = if Self::REVERSE_Z {
+        wgpu::CompareFunction::GreaterEqual
+    } else {
+        wgpu::CompareFunction::LessEqual
+    };
 
     pub fn create_depth_texture(
         device: &wgpu::Device,
@@ -43,14 +62,14 @@ impl Texture {
                 mag_filter: wgpu::FilterMode::Linear,
                 min_filter: wgpu::FilterMode::Linear,
                 mipmap_filter: wgpu::FilterMode::Nearest,
-                compare: Some(wgpu::CompareFunction::LessEqual),
+                compare: Some(Self::DEPTH_COMPARE_EQUAL),
                 lod_min_clamp: -100.0,
                 lod_max_clamp: 100.0,
                 ..Default::default()
             }
         );
 
-        Self {texture, view, sampler}
+        Self {texture, view, sampler, size, has_alpha: false}
 
     }
 
@@ -83,7 +102,7 @@ impl Texture {
 
         let texture = device.create_texture(
             &wgpu::TextureDescriptor {
-                size: size,
+                size,
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
@@ -127,7 +146,7 @@ impl Texture {
             }
         );
 
-        Self {texture, view, sampler}
+        Self {texture, view, sampler, size, has_alpha: img.color().has_alpha()}
     }
 
     
@@ -0,0 +1,211 @@
+use std::ops::Range;
+
+use cgmath::{Matrix4, MetricSpace, Point3};
+
+use crate::lod::LodSelector;
+use crate::InstanceRaw;
+
+// a mesh drawn with a blended material, and the instances of it using that
+// material that survived culling
+pub struct BlendedMesh<'a> {
+    pub mesh: usize,
+    pub material: usize,
+    // centre of the mesh in model space, used for the distance sort
+    pub center: Point3<f32>,
+    pub visible: &'a [usize],
+}
+
+// a run of sorted instances that can be drawn with one instanced call
+pub struct TransparentBatch {
+    pub mesh: usize,
+    pub material: usize,
+    pub lod: usize,
+    pub instances: Range<u32>,
+}
+
+// Blended geometry has to be drawn back to front. Every frame each
+// (mesh, instance) pair is sorted by distance to the camera and the
+// instance data is rewritten into a separate buffer in that order, so the
+// draws can index it with plain ranges.
+pub struct TransparencySorter {
+    instance_buffer: wgpu::Buffer,
+    capacity: usize,
+    pub batches: Vec<TransparentBatch>,
+}
+
+impl TransparencySorter {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            instance_buffer: Self::create_instance_buffer(device, 1),
+            capacity: 1,
+            batches: Vec::new(),
+        }
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Transparent Instance Buffer"),
+            size: (capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn instance_buffer(&self) -> &wgpu::Buffer {
+        &self.instance_buffer
+    }
+
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera_position: Point3<f32>,
+        instances: &[InstanceRaw],
+        meshes: &[BlendedMesh],
+        lods: &LodSelector,
+    ) {
+        let (instance_data, batches) = sort(camera_position, instances, meshes, lods);
+        self.batches = batches;
+        if instance_data.is_empty() {
+            return;
+        }
+
+        if instance_data.len() > self.capacity {
+            self.capacity = instance_data.len().next_power_of_two();
+            self.instance_buffer = Self::create_instance_buffer(device, self.capacity);
+        }
+        queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instance_data));
+    }
+}
+
+// The instance data furthest first and the batches to draw it with.
+fn sort(
+    camera_position: Point3<f32>,
+    instances: &[InstanceRaw],
+    meshes: &[BlendedMesh],
+    lods: &LodSelector,
+) -> (Vec<InstanceRaw>, Vec<TransparentBatch>) {
+    let mut draws = meshes
+        .iter()
+        .flat_map(|blended| blended.visible.iter().map(move |&i| (blended, i)))
+        .map(|(blended, i)| {
+            let raw = instances[i];
+            let model: Matrix4<f32> = raw.model.into();
+            let center = Point3::from_homogeneous(
+                model * blended.center.to_homogeneous()
+            );
+            let lod = lods.lod(blended.mesh, i);
+            let batch = (blended.mesh, blended.material, lod);
+            (camera_position.distance2(center), batch, raw)
+        })
+        .collect::<Vec<_>>();
+
+    // furthest first, keeping meshes of the same instance together
+    draws.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    let mut batches: Vec<TransparentBatch> = Vec::new();
+    for (i, &(_, key, _)) in draws.iter().enumerate() {
+        let i = i as u32;
+        let (mesh, material, lod) = key;
+        match batches.last_mut() {
+            Some(batch) if (batch.mesh, batch.material, batch.lod) == key => {
+                batch.instances.end = i + 1
+            }
+            _ => batches.push(TransparentBatch {
+                mesh,
+                material,
+                lod,
+                instances: i..i + 1,
+            }),
+        }
+    }
+    let instance_data = draws.into_iter().map(|(_, _, raw)| raw).collect();
+    (instance_data, batches)
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+
+    use super::*;
+    use crate::Instance;
+
+    fn at(x: f32) -> InstanceRaw {
+        Instance {
+            position: Vector3::new(x, 0.0, 0.0),
+            ..Default::default()
+        }
+        .to_raw()
+    }
+
+    fn mesh(mesh: usize, material: usize, visible: &[usize]) -> BlendedMesh<'_> {
+        BlendedMesh {
+            mesh,
+            material,
+            center: Point3::new(0.0, 0.0, 0.0),
+            visible,
+        }
+    }
+
+    fn batches(batches: &[TransparentBatch]) -> Vec<(usize, usize, u32, u32)> {
+        batches.iter()
+            .map(|batch| {
+                (batch.mesh, batch.material, batch.instances.start, batch.instances.end)
+            })
+            .collect()
+    }
+
+    fn xs(instance_data: &[InstanceRaw]) -> Vec<f32> {
+        instance_data.iter().map(|raw| raw.model[3][0]).collect()
+    }
+
+    #[test]
+    fn furthest_instances_come_first() {
+        let instances = [at(1.0), at(5.0), at(-3.0), at(2.0)];
+        let (instance_data, _) = sort(
+            Point3::new(0.0, 0.0, 0.0),
+            &instances,
+            &[mesh(0, 0, &[0, 1, 2, 3])],
+            &LodSelector::new(0.0),
+        );
+        assert_eq!(xs(&instance_data), [5.0, -3.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn ties_keep_the_meshes_of_an_instance_in_order() {
+        // both meshes share a centre, so each instance's are the same distance
+        let instances = [at(1.0), at(4.0)];
+        let (instance_data, sorted) = sort(
+            Point3::new(0.0, 0.0, 0.0),
+            &instances,
+            &[mesh(1, 0, &[0, 1]), mesh(0, 2, &[0, 1])],
+            &LodSelector::new(0.0),
+        );
+        assert_eq!(xs(&instance_data), [4.0, 4.0, 1.0, 1.0]);
+        assert_eq!(batches(&sorted), [(0, 2, 0, 1), (1, 0, 1, 2), (0, 2, 2, 3), (1, 0, 3, 4)]);
+    }
+
+    #[test]
+    fn neighbours_drawn_alike_share_a_batch() {
+        let instances = [at(1.0), at(2.0), at(3.0), at(4.0), at(5.0)];
+        let (_, sorted) = sort(
+            Point3::new(0.0, 0.0, 0.0),
+            &instances,
+            // 4 and 2 use another material, so break up the runs
+            &[mesh(0, 0, &[0, 2, 4]), mesh(0, 1, &[1, 3])],
+            &LodSelector::new(0.0),
+        );
+        assert_eq!(
+            batches(&sorted),
+            [(0, 0, 0, 1), (0, 1, 1, 2), (0, 0, 2, 3), (0, 1, 3, 4), (0, 0, 4, 5)],
+        );
+
+        let (_, sorted) = sort(
+            Point3::new(0.0, 0.0, 0.0),
+            &instances,
+            &[mesh(0, 0, &[3, 4]), mesh(0, 1, &[0, 1, 2])],
+            &LodSelector::new(0.0),
+        );
+        assert_eq!(batches(&sorted), [(0, 0, 0, 2), (0, 1, 2, 5)]);
+    }
+}